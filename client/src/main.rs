@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
 use crossterm::execute;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
@@ -13,16 +14,23 @@ use ratatui::style::{Color, Style};
 use ratatui::text::{Line, Span};
 use ratatui::widgets::{Block, Borders, Paragraph, Wrap};
 use ratatui::Terminal;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 
+use headless::ConnectOptions;
+use server::bot::BotConfig;
+use server::local::LocalServer;
+use shared::codec::Encoding;
+use shared::sim::{apply_movement, snap_angle, INTERMISSION_SECS, INTERPOLATION_DELAY_SECS, MAX_HEALTH, TICK_DT};
+use shared::snapshot::Snapshot;
 use shared::{
-    AsteroidState, BulletState, ClientMsg, PlayerInput, PlayerState, PowerUpKind, PowerUpState,
-    ServerMsg, WaveInfo,
+    AsteroidKind, AsteroidState, BossState, BulletState, ClientMsg, PlayerInput, PlayerState, PowerUpKind, PowerUpState,
+    CoopInfo, GameMode, MatchInfo, MatchPhase, RoomInfo, SafeZone, ServerMsg, Standing, TeamScore, UfoState, WaveInfo,
 };
-use shared::{Vec2, WORLD_HEIGHT, WORLD_WIDTH};
+use shared::{distance_squared_wrapped, shortest_delta, wrap_position, Vec2, WORLD_HEIGHT, WORLD_WIDTH};
 
+mod replay;
+
+#[derive(Default)]
 struct InputState {
     // 8-way directional movement
     up: bool,
@@ -40,23 +48,6 @@ struct InputState {
 
 const INPUT_TIMEOUT_MS: u64 = 120;
 
-impl Default for InputState {
-    fn default() -> Self {
-        Self {
-            up: false,
-            down: false,
-            left: false,
-            right: false,
-            fire: false,
-            up_at: None,
-            down_at: None,
-            left_at: None,
-            right_at: None,
-            fire_at: None,
-        }
-    }
-}
-
 impl InputState {
     fn clear(&mut self) {
         self.up = false;
@@ -123,18 +114,213 @@ impl InputState {
 }
 
 enum Mode {
+    /// Picking a room.
+    Lobby,
+    /// Answering a lobby question in the input line.
+    Prompt(Prompt),
     Game,
     Chat,
 }
 
+enum Prompt {
+    NewRoomName,
+    PrivateRoomName,
+    /// Left blank for a room that is only entered with its invite code.
+    PrivateRoomPassword { name: String },
+    JoinRoomName,
+    JoinRoomPassword { name: String },
+    InviteCode,
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Prompt::NewRoomName | Prompt::PrivateRoomName | Prompt::JoinRoomName => "room name",
+            Prompt::PrivateRoomPassword { .. } => "password (blank for invite only)",
+            Prompt::JoinRoomPassword { .. } => "password",
+            Prompt::InviteCode => "invite code",
+        }
+    }
+
+    fn is_secret(&self) -> bool {
+        matches!(self, Prompt::PrivateRoomPassword { .. } | Prompt::JoinRoomPassword { .. })
+    }
+}
+
+/// Modes offered for new rooms, cycled with `m` in the lobby.
+const NEW_ROOM_MODES: [GameMode; 5] = [
+    GameMode::FreeForAll,
+    GameMode::TeamDeathmatch { friendly_fire: false },
+    GameMode::TeamDeathmatch { friendly_fire: true },
+    GameMode::Coop,
+    GameMode::BattleRoyale,
+];
+
+/// How often the room list is refreshed while in the lobby.
+const LOBBY_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Upper bound on unacknowledged inputs, in case the server stops acking.
+const MAX_PENDING_INPUTS: usize = 64;
+
+/// Local prediction of our own ship. Every input is applied immediately and
+/// kept until the server acknowledges it, so it can be replayed on top of
+/// each authoritative state.
+#[derive(Default)]
+struct Prediction {
+    next_seq: u32,
+    pending: VecDeque<PlayerInput>,
+}
+
+/// Snapshots kept for interpolation and as baselines for incoming deltas.
+/// Matches the server's snapshot history.
+const SNAPSHOT_BUFFER_LEN: usize = 32;
+/// Entities that move further than this between two snapshots (respawns,
+/// spawned fragments) are snapped instead of sliding across the screen.
+const TELEPORT_DISTANCE: f32 = 10.0;
+
+/// Server simulation time of a snapshot, in seconds.
+fn snapshot_time(snapshot: &Snapshot) -> f64 {
+    snapshot.tick as f64 * TICK_DT as f64
+}
+
+/// Buffer of recent server states used to render remote entities smoothly
+/// between the 20 Hz updates.
+struct Interpolator {
+    snapshots: VecDeque<Snapshot>,
+    /// Estimated server time minus local time, in seconds.
+    clock_offset: Option<f64>,
+    started: Instant,
+    /// Render time set by replay playback, which keeps its own clock.
+    playback_time: Option<f64>,
+}
+
+impl Interpolator {
+    fn new() -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            clock_offset: None,
+            started: Instant::now(),
+            playback_time: None,
+        }
+    }
+
+    fn push(&mut self, snapshot: Snapshot) {
+        // Track the server clock, smoothing out network jitter but resyncing
+        // outright after a stall
+        let sample = snapshot_time(&snapshot) - self.started.elapsed().as_secs_f64();
+        self.clock_offset = match self.clock_offset {
+            Some(offset) if (sample - offset).abs() < 0.25 => {
+                Some(offset + (sample - offset) * 0.05)
+            }
+            _ => Some(sample),
+        };
+
+        if self.snapshots.back().is_some_and(|s| s.tick >= snapshot.tick) {
+            self.snapshots.clear();
+        }
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() > SNAPSHOT_BUFFER_LEN {
+            self.snapshots.pop_front();
+        }
+    }
+
+    fn newest_tick(&self) -> Option<u64> {
+        self.snapshots.back().map(|s| s.tick)
+    }
+
+    fn get(&self, tick: u64) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|s| s.tick == tick)
+    }
+
+    /// Returns the pair of snapshots around the current render time and how
+    /// far between them it lies.
+    fn frame(&self) -> Option<(&Snapshot, &Snapshot, f32)> {
+        let render_time = match self.playback_time {
+            Some(time) => time,
+            None => {
+                self.started.elapsed().as_secs_f64() + self.clock_offset? - INTERPOLATION_DELAY_SECS as f64
+            }
+        };
+        let newest = self.snapshots.back()?;
+        let mut from = self.snapshots.front()?;
+        let mut to = from;
+        for snapshot in &self.snapshots {
+            if snapshot_time(snapshot) <= render_time {
+                from = snapshot;
+            }
+            to = snapshot;
+            if snapshot_time(snapshot) > render_time {
+                break;
+            }
+        }
+        if render_time >= snapshot_time(newest) {
+            return Some((newest, newest, 0.0));
+        }
+        let span = snapshot_time(to) - snapshot_time(from);
+        let alpha = if span > 0.0 {
+            ((render_time - snapshot_time(from)) / span).clamp(0.0, 1.0) as f32
+        } else {
+            0.0
+        };
+        Some((from, to, alpha))
+    }
+}
+
+/// Wrap-aware linear interpolation between two world positions.
+fn lerp_wrapped(a: Vec2, b: Vec2, t: f32) -> Vec2 {
+    let dx = shortest_delta(b.x, a.x, WORLD_WIDTH);
+    let dy = shortest_delta(b.y, a.y, WORLD_HEIGHT);
+    if dx * dx + dy * dy > TELEPORT_DISTANCE * TELEPORT_DISTANCE {
+        return b;
+    }
+    wrap_position(Vec2::new(a.x + dx * t, a.y + dy * t))
+}
+
+/// Contents of the last `ServerMsg::MatchEnded`.
+struct MatchResults {
+    standings: Vec<Standing>,
+    teams: Vec<TeamScore>,
+    coop: Option<CoopInfo>,
+    wave: u32,
+}
+
+/// What the world view is centered on.
+#[derive(Clone, Copy)]
+enum Camera {
+    /// Our own ship.
+    Own,
+    /// Another player's ship, when watching a replay.
+    Follow(u64),
+    /// A point moved by hand, when watching a replay.
+    Free(Vec2),
+}
+
+/// How far the free camera moves per key press, in world units.
+const FREE_CAMERA_STEP: f32 = 4.0;
+
 struct ClientState {
     id: Option<u64>,
-    name: String,
+    /// Room we are playing in; `None` while in the lobby.
+    room: Option<RoomInfo>,
+    /// Set from asking to change rooms until the server answers.
+    switching_rooms: bool,
+    rooms: Vec<RoomInfo>,
+    selected_room: usize,
+    /// Index into `NEW_ROOM_MODES` for rooms we create.
+    new_room_mode: usize,
     players: HashMap<u64, PlayerState>,
     asteroids: Vec<AsteroidState>,
     bullets: Vec<BulletState>,
     power_ups: Vec<PowerUpState>,
+    ufos: Vec<UfoState>,
     wave: Option<WaveInfo>,
+    teams: Vec<TeamScore>,
+    coop: Option<CoopInfo>,
+    match_info: Option<MatchInfo>,
+    zone: Option<SafeZone>,
+    boss: Option<BossState>,
+    /// Shown until the next match warms up.
+    results: Option<MatchResults>,
     chat: Vec<String>,
     input: InputState,
     mode: Mode,
@@ -142,65 +328,274 @@ struct ClientState {
     should_quit: bool,
     death_flash_until: Option<Instant>,
     last_alive: bool,
+    prediction: Prediction,
+    interpolator: Interpolator,
+    camera: Camera,
+    /// Set while watching a replay.
+    replay: Option<replay::Status>,
 }
 
 impl ClientState {
-    fn new(name: String) -> Self {
+    fn new() -> Self {
         Self {
             id: None,
-            name,
+            room: None,
+            switching_rooms: false,
+            rooms: Vec::new(),
+            selected_room: 0,
+            new_room_mode: 0,
             players: HashMap::new(),
             asteroids: Vec::new(),
             bullets: Vec::new(),
             power_ups: Vec::new(),
+            ufos: Vec::new(),
             wave: None,
+            teams: Vec::new(),
+            coop: None,
+            match_info: None,
+            zone: None,
+            boss: None,
+            results: None,
             chat: Vec::new(),
             input: InputState::default(),
-            mode: Mode::Game,
+            mode: Mode::Lobby,
             chat_input: String::new(),
             should_quit: false,
             death_flash_until: None,
             last_alive: true,
+            prediction: Prediction::default(),
+            interpolator: Interpolator::new(),
+            camera: Camera::Own,
+            replay: None,
         }
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let (addr, name) = parse_args();
+    /// Asks the server to move us to another room. States from the current
+    /// one are no longer acked until it answers, as the acks could reach the
+    /// next room.
+    fn change_room(&mut self, out_tx: &mpsc::UnboundedSender<ClientMsg>, msg: ClientMsg) {
+        self.switching_rooms = true;
+        let _ = out_tx.send(msg);
+    }
+
+    /// Starts over with an empty arena for `room`. Input sequence numbers keep
+    /// counting so the server never mistakes new inputs for old ones.
+    fn enter_room(&mut self, room: Option<RoomInfo>) {
+        self.mode = if room.is_some() {
+            Mode::Game
+        } else {
+            Mode::Lobby
+        };
+        self.room = room;
+        self.players.clear();
+        self.asteroids.clear();
+        self.bullets.clear();
+        self.power_ups.clear();
+        self.ufos.clear();
+        self.wave = None;
+        self.teams.clear();
+        self.coop = None;
+        self.match_info = None;
+        self.zone = None;
+        self.boss = None;
+        self.results = None;
+        self.input.clear();
+        self.death_flash_until = None;
+        self.last_alive = true;
+        self.prediction.pending.clear();
+        self.interpolator = Interpolator::new();
+    }
+
+    /// Mode to return to after typing a chat line or room name.
+    fn idle_mode(&self) -> Mode {
+        if self.room.is_some() {
+            Mode::Game
+        } else {
+            Mode::Lobby
+        }
+    }
+
+    /// Player whose ship the view follows and whose status is shown.
+    fn viewed_id(&self) -> Option<u64> {
+        match self.camera {
+            Camera::Own => self.id,
+            Camera::Follow(id) => Some(id),
+            Camera::Free(_) => None,
+        }
+    }
 
-    let stream = TcpStream::connect(&addr).await?;
-    let (read_half, mut write_half) = stream.into_split();
+    /// World position at the middle of the view.
+    fn camera_center(&self) -> Vec2 {
+        if let Camera::Free(pos) = self.camera {
+            return pos;
+        }
+        self.viewed_id()
+            .and_then(|id| self.players.get(&id))
+            .map_or(Vec2::new(WORLD_WIDTH / 2.0, WORLD_HEIGHT / 2.0), |p| p.pos)
+    }
 
-    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<ClientMsg>();
-    let (in_tx, mut in_rx) = mpsc::unbounded_channel::<ServerMsg>();
+    /// Moves asteroids, bullets, UFOs and remote ships to where they were
+    /// [`INTERPOLATION_DELAY_SECS`] ago, interpolating between buffered snapshots. The
+    /// local ship is left to prediction.
+    fn interpolate(&mut self) {
+        let Some((from, to, alpha)) = self.interpolator.frame() else {
+            return;
+        };
 
-    tokio::spawn(async move {
-        while let Some(msg) = out_rx.recv().await {
-            if let Ok(line) = serde_json::to_string(&msg) {
-                if write_half.write_all(line.as_bytes()).await.is_err() {
-                    break;
+        let to_asteroids: HashMap<u64, Vec2> = to.asteroids.iter().map(|a| (a.id, a.pos)).collect();
+        self.asteroids = from
+            .asteroids
+            .iter()
+            .map(|a| {
+                let mut a = a.clone();
+                if let Some(&target) = to_asteroids.get(&a.id) {
+                    a.pos = lerp_wrapped(a.pos, target, alpha);
+                }
+                a
+            })
+            .collect();
+
+        let to_bullets: HashMap<u64, Vec2> = to.bullets.iter().map(|b| (b.id, b.pos)).collect();
+        self.bullets = from
+            .bullets
+            .iter()
+            .map(|b| {
+                let mut b = b.clone();
+                if let Some(&target) = to_bullets.get(&b.id) {
+                    b.pos = lerp_wrapped(b.pos, target, alpha);
                 }
-                if write_half.write_all(b"\n").await.is_err() {
-                    break;
+                b
+            })
+            .collect();
+
+        let to_ufos: HashMap<u64, Vec2> = to.ufos.iter().map(|u| (u.id, u.pos)).collect();
+        self.ufos = from
+            .ufos
+            .iter()
+            .map(|u| {
+                let mut u = u.clone();
+                if let Some(&target) = to_ufos.get(&u.id) {
+                    u.pos = lerp_wrapped(u.pos, target, alpha);
                 }
+                u
+            })
+            .collect();
+
+        let from_players: HashMap<u64, Vec2> = from.players.iter().map(|p| (p.id, p.pos)).collect();
+        let to_players: HashMap<u64, Vec2> = to.players.iter().map(|p| (p.id, p.pos)).collect();
+        for (id, player) in self.players.iter_mut() {
+            if Some(*id) == self.id {
+                continue;
+            }
+            if let (Some(&a), Some(&b)) = (from_players.get(id), to_players.get(id)) {
+                player.pos = lerp_wrapped(a, b, alpha);
             }
         }
-    });
+    }
 
-    tokio::spawn(async move {
-        let mut reader = BufReader::new(read_half).lines();
-        while let Ok(Some(line)) = reader.next_line().await {
-            if let Ok(msg) = serde_json::from_str::<ServerMsg>(&line) {
-                let _ = in_tx.send(msg);
+    /// Stamps `input` with the next sequence number and applies it to the
+    /// local ship straight away instead of waiting for the server.
+    fn predict(&mut self, mut input: PlayerInput) -> PlayerInput {
+        self.prediction.next_seq += 1;
+        input.seq = self.prediction.next_seq;
+        if let Some(player) = self.id.and_then(|id| self.players.get_mut(&id)) {
+            if player.alive {
+                let boost = player.effects.speed_boost_remaining.is_some();
+                apply_movement(player, &input, boost, TICK_DT);
             }
         }
-    });
+        self.prediction.pending.push_back(input.clone());
+        if self.prediction.pending.len() > MAX_PENDING_INPUTS {
+            self.prediction.pending.pop_front();
+        }
+        input
+    }
 
-    out_tx.send(ClientMsg::Join { name: name.clone() })?;
+    /// Drops inputs the server has applied and replays the rest on top of the
+    /// authoritative copy of the local ship.
+    fn reconcile(&mut self) {
+        let Some(player) = self.id.and_then(|id| self.players.get_mut(&id)) else {
+            return;
+        };
+        let acked = player.last_input_seq;
+        self.prediction.pending.retain(|input| input.seq > acked);
+        if !player.alive {
+            return;
+        }
+        let boost = player.effects.speed_boost_remaining.is_some();
+        for input in &self.prediction.pending {
+            apply_movement(player, input, boost, TICK_DT);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args();
+    if let Some(path) = &args.replay {
+        return watch_replay(path).await;
+    }
+
+    let (out_tx, mut in_rx) = if args.offline {
+        connect_offline(&args).await
+    } else {
+        let options = ConnectOptions {
+            addr: args.addr.clone(),
+            name: args.name.clone(),
+            encoding: args.encoding,
+            udp: args.udp,
+        };
+        let connection = headless::connect(&options).await?;
+        (connection.tx, connection.rx)
+    };
 
     let mut tui = Tui::new()?;
-    let (event_tx, mut event_rx) = mpsc::unbounded_channel::<Event>();
+    let mut event_rx = read_terminal_events();
+
+    let mut state = ClientState::new();
+    let mut render_tick = tokio::time::interval(Duration::from_millis(33));
+    // Inputs are sampled once per server tick so each one maps onto exactly
+    // one simulation step on both sides
+    let mut input_tick = tokio::time::interval(Duration::from_secs_f32(TICK_DT));
+    let mut lobby_refresh = tokio::time::interval(LOBBY_REFRESH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            Some(msg) = in_rx.recv() => {
+                handle_server_msg(&mut state, msg, &out_tx);
+            }
+            Some(ev) = event_rx.recv() => {
+                handle_event(&mut state, ev, &out_tx)?;
+            }
+            _ = input_tick.tick(), if state.room.is_some() => {
+                let input_msg = if let Mode::Game = state.mode {
+                    build_input(&mut state.input)
+                } else {
+                    PlayerInput::default()
+                };
+                let input_msg = state.predict(input_msg);
+                let _ = out_tx.send(ClientMsg::Input(input_msg));
+            }
+            _ = lobby_refresh.tick(), if state.room.is_none() => {
+                let _ = out_tx.send(ClientMsg::ListRooms);
+            }
+            _ = render_tick.tick() => {
+                state.interpolate();
+                tui.draw(&state)?;
+            }
+        }
+
+        if state.should_quit {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forwards key presses and other terminal events from a blocking thread.
+fn read_terminal_events() -> mpsc::UnboundedReceiver<Event> {
+    let (event_tx, event_rx) = mpsc::unbounded_channel::<Event>();
     tokio::task::spawn_blocking(move || loop {
         if event::poll(Duration::from_millis(5)).unwrap_or(false) {
             if let Ok(ev) = event::read() {
@@ -208,28 +603,55 @@ async fn main() -> Result<()> {
             }
         }
     });
+    event_rx
+}
 
-    let mut state = ClientState::new(name);
+/// Plays a recorded match in the game view instead of connecting anywhere.
+async fn watch_replay(path: &Path) -> Result<()> {
+    let bytes = std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+    let (header, msgs) = shared::replay::decode(&bytes)?;
+    let Some(mut playback) = replay::Playback::new(msgs) else {
+        anyhow::bail!("{} holds no game states", path.display());
+    };
+
+    let mut state = ClientState::new();
+    state.enter_room(Some(RoomInfo {
+        id: 0,
+        name: header.room,
+        players: 0,
+        max_players: 0,
+        mode: header.mode,
+    }));
+    state.chat.push(format!("* watching {}", path.display()));
+    // Acks and pongs for recorded messages have nowhere to go
+    let (out_tx, _) = mpsc::unbounded_channel();
+    for msg in playback.advance(Duration::ZERO) {
+        handle_server_msg(&mut state, msg, &out_tx);
+    }
+    state.camera = match state.players.keys().min() {
+        Some(&id) => Camera::Follow(id),
+        None => Camera::Free(state.camera_center()),
+    };
+
+    let mut tui = Tui::new()?;
+    let mut event_rx = read_terminal_events();
     let mut render_tick = tokio::time::interval(Duration::from_millis(33));
-    let mut input_tick = tokio::time::interval(Duration::from_millis(16));
+    let mut last_frame = Instant::now();
 
     loop {
         tokio::select! {
-            Some(msg) = in_rx.recv() => {
-                handle_server_msg(&mut state, msg);
-            }
             Some(ev) = event_rx.recv() => {
-                handle_event(&mut state, ev, &out_tx)?;
-            }
-            _ = input_tick.tick() => {
-                if let Mode::Game = state.mode {
-                    let input_msg = build_input(&mut state.input);
-                    let _ = out_tx.send(ClientMsg::Input(input_msg));
-                } else {
-                    let _ = out_tx.send(ClientMsg::Input(PlayerInput::default()));
-                }
+                handle_replay_event(&mut state, &mut playback, ev, &out_tx);
             }
             _ = render_tick.tick() => {
+                let now = Instant::now();
+                for msg in playback.advance(now - last_frame) {
+                    handle_server_msg(&mut state, msg, &out_tx);
+                }
+                last_frame = now;
+                state.interpolator.playback_time = Some(playback.time());
+                state.replay = Some(playback.status());
+                state.interpolate();
                 tui.draw(&state)?;
             }
         }
@@ -242,66 +664,117 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Starts a server on this process's runtime and joins it over channels, so
+/// the game runs without any network.
+async fn connect_offline(
+    args: &Args,
+) -> (mpsc::UnboundedSender<ClientMsg>, mpsc::UnboundedReceiver<ServerMsg>) {
+    let server = LocalServer::new(rand::random(), server::default_rules(), args.bots);
+    server.connect(args.name.clone()).await
+}
+
 fn build_input(input: &mut InputState) -> PlayerInput {
     // On platforms without Release events (macOS), auto-clear after timeout
     input.check_timeout();
 
     // 8-way directional: compute target angle from WASD
     if let Some(angle) = input.to_direction() {
-        // Snap to 8 directions for consistency
-        let sector = ((angle + std::f32::consts::FRAC_PI_8) / std::f32::consts::FRAC_PI_4).floor();
-        let snapped = sector * std::f32::consts::FRAC_PI_4;
-
         PlayerInput {
             thrust: true,
-            target_angle: Some(snapped),
+            target_angle: Some(snap_angle(angle)),
             fire: input.fire,
+            seq: 0,
         }
     } else {
         PlayerInput {
             thrust: false,
             target_angle: None,
             fire: input.fire,
+            seq: 0,
         }
     }
 }
 
-fn handle_server_msg(state: &mut ClientState, msg: ServerMsg) {
+fn handle_server_msg(
+    state: &mut ClientState,
+    msg: ServerMsg,
+    out_tx: &mpsc::UnboundedSender<ClientMsg>,
+) {
     match msg {
         ServerMsg::Welcome { id, .. } => {
             state.id = Some(id);
             state.chat.push(format!("connected as id {id}"));
         }
-        ServerMsg::State {
-            players,
-            asteroids,
-            bullets,
-            power_ups,
-            wave,
-        } => {
-            // Check if local player just died (was alive, now dead)
-            if let Some(id) = state.id {
-                let was_alive = state.last_alive;
-                let now_alive = players.iter().find(|p| p.id == id).map_or(false, |p| p.alive);
-                if was_alive && !now_alive {
-                    state.death_flash_until = Some(Instant::now() + Duration::from_millis(500));
+        ServerMsg::State(snapshot) => {
+            // Over UDP, and when keyframes overtake datagrams, states can
+            // arrive out of order
+            if state.room.is_none() || state.interpolator.newest_tick() >= Some(snapshot.tick) {
+                return;
+            }
+            if !state.switching_rooms {
+                let _ = out_tx.send(ClientMsg::Ack { tick: snapshot.tick });
+            }
+            apply_snapshot(state, snapshot);
+        }
+        ServerMsg::Delta(delta) => {
+            // A delta against a snapshot we no longer hold is dropped; our acks
+            // stop advancing and the server falls back to a keyframe
+            if state.room.is_none() || state.interpolator.newest_tick() >= Some(delta.tick) {
+                return;
+            }
+            let snapshot = state
+                .interpolator
+                .get(delta.base_tick)
+                .and_then(|base| base.apply(&delta));
+            if let Some(snapshot) = snapshot {
+                if !state.switching_rooms {
+                    let _ = out_tx.send(ClientMsg::Ack { tick: snapshot.tick });
                 }
-                state.last_alive = now_alive;
+                apply_snapshot(state, snapshot);
             }
-
-            state.players = players.into_iter().map(|p| (p.id, p)).collect();
-            state.asteroids = asteroids;
-            state.bullets = bullets;
-            state.power_ups = power_ups;
-            state.wave = wave;
         }
         ServerMsg::Chat { from, text } => {
             state.chat.push(format!("{from}: {text}"));
         }
         ServerMsg::System { text } => {
+            // A room we could not get into is answered with a notice, and we
+            // stay where we are
+            state.switching_rooms = false;
             state.chat.push(format!("* {text}"));
         }
+        ServerMsg::Rejected { reason } => {
+            state.chat.push(format!("* rejected by server: {reason}"));
+        }
         ServerMsg::Pong { .. } => {}
+        ServerMsg::Ping { nonce } => {
+            let _ = out_tx.send(ClientMsg::Pong { nonce });
+        }
+        ServerMsg::RoomList { rooms } => {
+            state.selected_room = state.selected_room.min(rooms.len().saturating_sub(1));
+            state.rooms = rooms;
+        }
+        ServerMsg::RoomJoined { room } => {
+            state.chat.push(format!("* entered {}", room.name));
+            state.switching_rooms = false;
+            state.enter_room(Some(room));
+        }
+        ServerMsg::RoomLeft => {
+            state.switching_rooms = false;
+            state.enter_room(None);
+        }
+        ServerMsg::MatchEnded {
+            standings,
+            teams,
+            coop,
+            wave,
+        } => {
+            state.results = Some(MatchResults {
+                standings,
+                teams,
+                coop,
+                wave,
+            });
+        }
     }
     if state.chat.len() > 200 {
         let extra = state.chat.len() - 200;
@@ -309,17 +782,138 @@ fn handle_server_msg(state: &mut ClientState, msg: ServerMsg) {
     }
 }
 
+fn apply_snapshot(state: &mut ClientState, snapshot: Snapshot) {
+    // Check if local player just died (was alive, now dead)
+    if let Some(id) = state.id {
+        let was_alive = state.last_alive;
+        let now_alive = snapshot
+            .players
+            .iter()
+            .find(|p| p.id == id)
+            .is_some_and(|p| p.alive);
+        if was_alive && !now_alive {
+            state.death_flash_until = Some(Instant::now() + Duration::from_millis(500));
+        }
+        state.last_alive = now_alive;
+    }
+
+    state.interpolator.push(snapshot.clone());
+
+    let Snapshot {
+        players,
+        asteroids,
+        bullets,
+        power_ups,
+        ufos,
+        wave,
+        teams,
+        coop,
+        match_info,
+        zone,
+        boss,
+        ..
+    } = snapshot;
+    state.players = players.into_iter().map(|p| (p.id, p)).collect();
+    state.reconcile();
+    state.asteroids = asteroids;
+    state.bullets = bullets;
+    state.power_ups = power_ups;
+    state.ufos = ufos;
+    state.wave = wave;
+    state.teams = teams;
+    state.coop = coop;
+    if match_info.as_ref().is_some_and(|m| m.phase.is_live()) {
+        state.results = None;
+    }
+    state.match_info = match_info;
+    state.zone = zone;
+    state.boss = boss;
+}
+
 fn handle_event(state: &mut ClientState, ev: Event, out_tx: &mpsc::UnboundedSender<ClientMsg>) -> Result<()> {
-    match ev {
-        Event::Key(key) => match state.mode {
+    if let Event::Key(key) = ev {
+        match state.mode {
+            Mode::Lobby => handle_lobby_key(state, key, out_tx)?,
+            Mode::Prompt(_) => handle_prompt_key(state, key, out_tx)?,
             Mode::Chat => handle_chat_key(state, key, out_tx)?,
-            Mode::Game => handle_game_key(state, key)?,
-        },
-        _ => {}
+            Mode::Game => handle_game_key(state, key, out_tx)?,
+        }
     }
     Ok(())
 }
 
+fn handle_replay_event(
+    state: &mut ClientState,
+    playback: &mut replay::Playback,
+    ev: Event,
+    out_tx: &mpsc::UnboundedSender<ClientMsg>,
+) {
+    let Event::Key(key) = ev else {
+        return;
+    };
+    if key.kind == KeyEventKind::Release {
+        return;
+    }
+    let seek = match key.code {
+        KeyCode::Left => -REPLAY_SEEK_SECS,
+        KeyCode::Right => REPLAY_SEEK_SECS,
+        KeyCode::Home => f64::NEG_INFINITY,
+        KeyCode::End => f64::INFINITY,
+        _ => {
+            handle_replay_key(state, playback, key);
+            return;
+        }
+    };
+    let msgs = playback.seek(seek);
+    // Start over from the keyframe the playback went back to
+    let room = state.room.take();
+    state.enter_room(room);
+    for msg in msgs {
+        handle_server_msg(state, msg, out_tx);
+    }
+}
+
+fn handle_replay_key(state: &mut ClientState, playback: &mut replay::Playback, key: crossterm::event::KeyEvent) {
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => state.should_quit = true,
+        KeyCode::Char(' ') => playback.toggle_pause(),
+        KeyCode::Char('+') | KeyCode::Char('=') => playback.faster(),
+        KeyCode::Char('-') => playback.slower(),
+        KeyCode::Tab | KeyCode::BackTab => {
+            let mut ids: Vec<u64> = state.players.keys().copied().collect();
+            ids.sort_unstable();
+            if key.code == KeyCode::BackTab {
+                ids.reverse();
+            }
+            let next = match state.camera {
+                Camera::Follow(current) => {
+                    let after = ids.iter().position(|&id| id == current).map_or(0, |i| i + 1);
+                    ids.get(after).or(ids.first())
+                }
+                _ => ids.first(),
+            };
+            if let Some(&id) = next {
+                state.camera = Camera::Follow(id);
+            }
+        }
+        KeyCode::Char('f') => state.camera = Camera::Free(state.camera_center()),
+        KeyCode::Char(c @ ('w' | 'a' | 's' | 'd')) => {
+            let (dx, dy) = match c {
+                'w' => (0.0, -1.0),
+                's' => (0.0, 1.0),
+                'a' => (-1.0, 0.0),
+                _ => (1.0, 0.0),
+            };
+            let center = state.camera_center();
+            state.camera = Camera::Free(wrap_position(Vec2::new(
+                center.x + dx * FREE_CAMERA_STEP,
+                center.y + dy * FREE_CAMERA_STEP,
+            )));
+        }
+        _ => {}
+    }
+}
+
 fn handle_chat_key(
     state: &mut ClientState,
     key: crossterm::event::KeyEvent,
@@ -327,7 +921,7 @@ fn handle_chat_key(
 ) -> Result<()> {
     match key.code {
         KeyCode::Esc => {
-            state.mode = Mode::Game;
+            state.mode = state.idle_mode();
             state.chat_input.clear();
             state.input.clear();
         }
@@ -337,15 +931,50 @@ fn handle_chat_key(
                 let _ = out_tx.send(ClientMsg::Chat { text });
             }
             state.chat_input.clear();
-            state.mode = Mode::Game;
+            state.mode = state.idle_mode();
             state.input.clear();
         }
         KeyCode::Backspace => {
             state.chat_input.pop();
         }
-        KeyCode::Char(c) => {
-            if !key.modifiers.contains(KeyModifiers::CONTROL) {
-                state.chat_input.push(c);
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+            state.chat_input.push(c);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn handle_lobby_key(
+    state: &mut ClientState,
+    key: crossterm::event::KeyEvent,
+    out_tx: &mpsc::UnboundedSender<ClientMsg>,
+) -> Result<()> {
+    if key.kind == KeyEventKind::Release {
+        return Ok(());
+    }
+    match key.code {
+        KeyCode::Char('q') => state.should_quit = true,
+        KeyCode::Char('c') => state.mode = Mode::Chat,
+        KeyCode::Char('n') => state.mode = Mode::Prompt(Prompt::NewRoomName),
+        KeyCode::Char('p') => state.mode = Mode::Prompt(Prompt::PrivateRoomName),
+        KeyCode::Char('j') => state.mode = Mode::Prompt(Prompt::JoinRoomName),
+        KeyCode::Char('i') => state.mode = Mode::Prompt(Prompt::InviteCode),
+        KeyCode::Char('r') => {
+            let _ = out_tx.send(ClientMsg::ListRooms);
+        }
+        KeyCode::Char('m') => {
+            state.new_room_mode = (state.new_room_mode + 1) % NEW_ROOM_MODES.len();
+        }
+        KeyCode::Char('w') | KeyCode::Up => {
+            state.selected_room = state.selected_room.saturating_sub(1);
+        }
+        KeyCode::Char('s') | KeyCode::Down => {
+            state.selected_room = (state.selected_room + 1).min(state.rooms.len().saturating_sub(1));
+        }
+        KeyCode::Enter => {
+            if let Some(room_id) = state.rooms.get(state.selected_room).map(|r| r.id) {
+                state.change_room(out_tx, ClientMsg::JoinRoom { room_id });
             }
         }
         _ => {}
@@ -353,12 +982,72 @@ fn handle_chat_key(
     Ok(())
 }
 
-fn handle_game_key(state: &mut ClientState, key: crossterm::event::KeyEvent) -> Result<()> {
+fn handle_prompt_key(
+    state: &mut ClientState,
+    key: crossterm::event::KeyEvent,
+    out_tx: &mpsc::UnboundedSender<ClientMsg>,
+) -> Result<()> {
+    match key.code {
+        KeyCode::Esc => {
+            state.mode = Mode::Lobby;
+            state.chat_input.clear();
+        }
+        KeyCode::Enter => {
+            let answer = std::mem::take(&mut state.chat_input);
+            let Mode::Prompt(prompt) = std::mem::replace(&mut state.mode, Mode::Lobby) else {
+                return Ok(());
+            };
+            let name = answer.trim().to_string();
+            let mode = NEW_ROOM_MODES[state.new_room_mode];
+            match prompt {
+                Prompt::NewRoomName if !name.is_empty() => {
+                    state.change_room(out_tx, ClientMsg::CreateRoom { name, mode });
+                }
+                Prompt::PrivateRoomName if !name.is_empty() => {
+                    state.mode = Mode::Prompt(Prompt::PrivateRoomPassword { name });
+                }
+                Prompt::PrivateRoomPassword { name } => {
+                    let password = (!answer.is_empty()).then_some(answer);
+                    state.change_room(out_tx, ClientMsg::CreatePrivateRoom { name, password, mode });
+                }
+                Prompt::JoinRoomName if !name.is_empty() => {
+                    state.mode = Mode::Prompt(Prompt::JoinRoomPassword { name });
+                }
+                Prompt::JoinRoomPassword { name } => {
+                    state.change_room(out_tx, ClientMsg::JoinPrivateRoom { name, password: answer });
+                }
+                Prompt::InviteCode if !name.is_empty() => {
+                    state.change_room(out_tx, ClientMsg::JoinByInvite { code: name });
+                }
+                _ => {}
+            }
+        }
+        KeyCode::Backspace => {
+            state.chat_input.pop();
+        }
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+            state.chat_input.push(c);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn handle_game_key(
+    state: &mut ClientState,
+    key: crossterm::event::KeyEvent,
+    out_tx: &mpsc::UnboundedSender<ClientMsg>,
+) -> Result<()> {
     if key.code == KeyCode::Char('q') {
         state.should_quit = true;
         return Ok(());
     }
 
+    if key.code == KeyCode::Char('l') {
+        let _ = out_tx.send(ClientMsg::LeaveRoom);
+        return Ok(());
+    }
+
     if key.code == KeyCode::Char('c') {
         state.mode = Mode::Chat;
         state.input.clear();
@@ -441,6 +1130,17 @@ impl Tui {
         self.terminal.draw(|f| {
             let size = f.size();
 
+            if state.room.is_none() {
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(10), Constraint::Length(7)])
+                    .split(size);
+                f.render_widget(render_lobby(state), chunks[0]);
+                let chat = render_chat(chunks[1], state);
+                f.render_widget(chat, chunks[1]);
+                return;
+            }
+
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .constraints([Constraint::Min(10), Constraint::Length(7)])
@@ -454,21 +1154,30 @@ impl Tui {
             // Death flash: red border when recently died
             let border_style = if state
                 .death_flash_until
-                .map_or(false, |t| Instant::now() < t)
+                .is_some_and(|t| Instant::now() < t)
             {
                 Style::default().fg(Color::Red)
             } else {
                 Style::default()
             };
 
+            let title = match &state.room {
+                Some(room) if state.replay.is_some() => format!("Replay - {} ({})", room.name, room.mode),
+                Some(room) => format!("Asteroids - {} ({})", room.name, room.mode),
+                None => "Asteroids".to_string(),
+            };
             let game_block = Block::default()
                 .borders(Borders::ALL)
-                .title("Asteroids")
+                .title(title)
                 .border_style(border_style);
-            let inner = game_block.inner(top[0]);
-            let lines = build_world_lines(inner, state);
-            let game = Paragraph::new(lines).block(game_block);
-            f.render_widget(game, top[0]);
+            if let Some(results) = &state.results {
+                f.render_widget(render_results(results, state, game_block), top[0]);
+            } else {
+                let inner = game_block.inner(top[0]);
+                let lines = build_world_lines(inner, state);
+                let game = Paragraph::new(lines).block(game_block);
+                f.render_widget(game, top[0]);
+            }
 
             let scoreboard = render_scoreboard(state);
             f.render_widget(scoreboard, top[1]);
@@ -497,11 +1206,7 @@ fn build_world_lines(area: Rect, state: &ClientState) -> Vec<Line<'static>> {
 
     let mut grid = vec![vec![Cell { ch: ' ', style: Style::default() }; w]; h];
 
-    let center = if let Some(id) = state.id {
-        state.players.get(&id).map(|p| p.pos).unwrap_or(Vec2::new(WORLD_WIDTH / 2.0, WORLD_HEIGHT / 2.0))
-    } else {
-        Vec2::new(WORLD_WIDTH / 2.0, WORLD_HEIGHT / 2.0)
-    };
+    let center = state.camera_center();
 
     let set_cell = |grid: &mut Vec<Vec<Cell>>, x: usize, y: usize, ch: char, style: Style| {
         if let Some(row) = grid.get_mut(y) {
@@ -512,17 +1217,43 @@ fn build_world_lines(area: Rect, state: &ClientState) -> Vec<Line<'static>> {
         }
     };
 
+    // Battle royale zone: its edge, and a faint pattern over the world outside it
+    if let Some(zone) = &state.zone {
+        let view_w = area.width as f32 / VIEW_ZOOM;
+        let view_h = area.height as f32 / VIEW_ZOOM;
+        let cell_w = view_w / (w as f32 - 1.0).max(1.0);
+        let cell_h = view_h / (h as f32 - 1.0).max(1.0);
+        let edge = cell_w.max(cell_h);
+        for (y, row) in grid.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let pos = Vec2::new(
+                    center.x + x as f32 * cell_w - view_w / 2.0,
+                    center.y + y as f32 * cell_h - view_h / 2.0,
+                );
+                let dist = distance_squared_wrapped(pos, zone.center).sqrt();
+                if (dist - zone.radius).abs() < edge / 2.0 {
+                    cell.ch = '+';
+                    cell.style = Style::default().fg(Color::LightMagenta);
+                } else if dist > zone.radius && (x + y) % 2 == 0 {
+                    cell.ch = '·';
+                    cell.style = Style::default().fg(Color::Red);
+                }
+            }
+        }
+    }
+
     for ast in &state.asteroids {
         if let Some((x, y)) = world_to_view(ast.pos, center, area) {
-            let style = Style::default().fg(Color::Yellow);
+            let style = Style::default().fg(asteroid_color(ast.kind));
+            let ch = asteroid_glyph(ast);
             match ast.size {
                 1 => {
-                    set_cell(&mut grid, x, y, 'o', style);
+                    set_cell(&mut grid, x, y, ch, style);
                 }
                 2 => {
-                    set_cell(&mut grid, x, y, 'O', style);
-                    if x > 0 { set_cell(&mut grid, x - 1, y, 'O', style); }
-                    set_cell(&mut grid, x + 1, y, 'O', style);
+                    set_cell(&mut grid, x, y, ch, style);
+                    if x > 0 { set_cell(&mut grid, x - 1, y, ch, style); }
+                    set_cell(&mut grid, x + 1, y, ch, style);
                 }
                 _ => {
                     // Large asteroid: 3x2 block
@@ -530,17 +1261,54 @@ fn build_world_lines(area: Rect, state: &ClientState) -> Vec<Line<'static>> {
                         for dx_off in -1i32..=1 {
                             let nx = (x as i32 + dx_off) as usize;
                             let ny = y + dy;
-                            set_cell(&mut grid, nx, ny, '#', style);
+                            set_cell(&mut grid, nx, ny, ch, style);
                         }
                     }
                 }
             };
+            if let Some(marker) = asteroid_marker(ast.kind) {
+                set_cell(&mut grid, x, y, marker, style);
+            }
+        }
+    }
+
+    if let Some(boss) = &state.boss {
+        if let Some((x, y)) = world_to_view(boss.pos, center, area) {
+            let style = Style::default().fg(Color::LightRed);
+            for (dy, row) in BOSS_SPRITE.iter().enumerate() {
+                for (dx, ch) in row.chars().enumerate() {
+                    if ch == ' ' {
+                        continue;
+                    }
+                    let nx = x as i32 + dx as i32 - BOSS_SPRITE[0].len() as i32 / 2;
+                    let ny = y as i32 + dy as i32 - BOSS_SPRITE.len() as i32 / 2;
+                    if nx >= 0 && ny >= 0 {
+                        set_cell(&mut grid, nx as usize, ny as usize, ch, style);
+                    }
+                }
+            }
+        }
+    }
+
+    for ufo in &state.ufos {
+        if let Some((x, y)) = world_to_view(ufo.pos, center, area) {
+            let style = Style::default().fg(Color::LightMagenta);
+            if x > 0 {
+                set_cell(&mut grid, x - 1, y, '<', style);
+            }
+            set_cell(&mut grid, x, y, 'o', style);
+            set_cell(&mut grid, x + 1, y, '>', style);
         }
     }
 
     for bullet in &state.bullets {
         if let Some((x, y)) = world_to_view(bullet.pos, center, area) {
-            set_cell(&mut grid, x, y, '*', Style::default().fg(Color::Red));
+            let color = if bullet.enemy {
+                Color::LightMagenta
+            } else {
+                Color::Red
+            };
+            set_cell(&mut grid, x, y, '*', Style::default().fg(color));
         }
     }
 
@@ -557,9 +1325,10 @@ fn build_world_lines(area: Rect, state: &ClientState) -> Vec<Line<'static>> {
         }
     }
 
-    let self_id = state.id;
-    // Use milliseconds for blinking effect
-    let blink_on = (Instant::now().elapsed().as_millis() / 150) % 2 == 0;
+    let self_id = state.viewed_id();
+    // Blink every 150 ms of wall-clock time
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let blink_on = (millis / 150) % 2 == 1;
 
     for player in state.players.values() {
         if !player.alive {
@@ -572,18 +1341,16 @@ fn build_world_lines(area: Rect, state: &ClientState) -> Vec<Line<'static>> {
                 'A'
             };
 
-            // Check if player is invincible (has invincible_remaining or shield_remaining)
+            // Blink while spawn-protected or shielded
             let is_invincible = player.effects.invincible_remaining.is_some()
                 || player.effects.shield_remaining.is_some();
 
-            let style = if Some(player.id) == self_id {
-                if is_invincible && !blink_on {
-                    Style::default().fg(Color::White)
-                } else {
-                    Style::default().fg(Color::Green)
-                }
-            } else if is_invincible && !blink_on {
+            let style = if is_invincible && !blink_on {
                 Style::default().fg(Color::White)
+            } else if let Some(team) = player.team {
+                Style::default().fg(team_color(team))
+            } else if Some(player.id) == self_id {
+                Style::default().fg(Color::Green)
             } else {
                 Style::default().fg(Color::Cyan)
             };
@@ -594,14 +1361,44 @@ fn build_world_lines(area: Rect, state: &ClientState) -> Vec<Line<'static>> {
     grid_to_lines(grid)
 }
 
-fn shortest_delta(a: f32, b: f32, wrap: f32) -> f32 {
-    let mut d = a - b;
-    if d > wrap / 2.0 {
-        d -= wrap;
-    } else if d < -wrap / 2.0 {
-        d += wrap;
+/// The boss's mothership, drawn centred on its position.
+const BOSS_SPRITE: [&str; 5] = [
+    "  /=====\\  ",
+    " /|#####|\\ ",
+    "<=|#(@)#|=>",
+    " \\|#####|/ ",
+    "  \\=====/  ",
+];
+
+/// Asteroids wear down as they are hit: one glyph per hit point, starting
+/// from undamaged.
+fn asteroid_glyph(ast: &AsteroidState) -> char {
+    let glyphs: &[char] = match ast.size {
+        1 => &['o'],
+        2 => &['O', '0'],
+        _ => &['#', '%', '=', ':'],
+    };
+    glyphs[(ast.damage as usize).min(glyphs.len() - 1)]
+}
+
+fn asteroid_color(kind: AsteroidKind) -> Color {
+    match kind {
+        AsteroidKind::Normal => Color::Yellow,
+        AsteroidKind::Explosive => Color::LightRed,
+        AsteroidKind::Magnetic => Color::LightBlue,
+        AsteroidKind::Cluster => Color::LightGreen,
+    }
+}
+
+/// Drawn over the middle of special asteroids so their kind shows without
+/// colour too.
+fn asteroid_marker(kind: AsteroidKind) -> Option<char> {
+    match kind {
+        AsteroidKind::Normal => None,
+        AsteroidKind::Explosive => Some('!'),
+        AsteroidKind::Magnetic => Some('@'),
+        AsteroidKind::Cluster => Some('&'),
     }
-    d
 }
 
 const VIEW_ZOOM: f32 = 1.3; // Higher = more zoomed in, objects appear bigger
@@ -687,8 +1484,54 @@ fn render_scoreboard(state: &ClientState) -> Paragraph<'static> {
         )));
     }
 
-    // Player's own status
-    if let Some(id) = state.id {
+    if let Some(ref boss) = state.boss {
+        let mut spans = vec![Span::styled("BOSS [", Style::default().fg(Color::LightRed))];
+        spans.extend(meter(boss.health / boss.max_health, HEALTH_BAR_WIDTH, Color::LightRed));
+        spans.push(Span::styled(
+            format!("] {:.0}", boss.health.ceil()),
+            Style::default().fg(Color::LightRed),
+        ));
+        lines.push(Line::from(spans));
+    }
+
+    if let Some(ref info) = state.match_info {
+        let (text, color) = match (info.phase, info.countdown) {
+            (MatchPhase::Warmup, Some(t)) => (format!("Warmup - {t:.0}s"), Color::Yellow),
+            (MatchPhase::InProgress, Some(t)) => {
+                let secs = t.ceil() as u32;
+                (format!("Time left {}:{:02}", secs / 60, secs % 60), Color::Green)
+            }
+            (MatchPhase::Ended | MatchPhase::Intermission, Some(t)) => {
+                (format!("Match over - {t:.0}s"), Color::Red)
+            }
+            _ => (String::new(), Color::Reset),
+        };
+        if !text.is_empty() {
+            lines.push(Line::from(Span::styled(text, Style::default().fg(color))));
+        }
+        if let Some(limit) = info.score_limit {
+            lines.push(Line::from(format!("First to {limit}")));
+        }
+    }
+
+    if let Some(ref coop) = state.coop {
+        if coop.lives == 0 {
+            lines.push(Line::from(Span::styled(
+                "RUN OVER",
+                Style::default().fg(Color::Red),
+            )));
+            lines.push(Line::from(format!("Combined score: {}", coop.score)));
+        } else {
+            lines.push(Line::from(Span::styled(
+                format!("Lives: {}", coop.lives),
+                Style::default().fg(Color::Green),
+            )));
+            lines.push(Line::from(format!("Combined score: {}", coop.score)));
+        }
+    }
+
+    // Status of our own ship, or the one followed in a replay
+    if let Some(id) = state.viewed_id() {
         if let Some(player) = state.players.get(&id) {
             // Score and combo
             let combo_str = if player.combo > 1 {
@@ -697,6 +1540,34 @@ fn render_scoreboard(state: &ClientState) -> Paragraph<'static> {
                 String::new()
             };
             lines.push(Line::from(format!("Score: {}{}", player.score, combo_str)));
+            if player.alive {
+                lines.push(health_bar(player.health, player.shield));
+            }
+
+            if let Some(team) = player.team {
+                lines.push(Line::from(Span::styled(
+                    format!("Team: {}", team_name(team)),
+                    Style::default().fg(team_color(team)),
+                )));
+            }
+
+            if let Some(ref zone) = state.zone {
+                lines.push(Line::from(Span::styled(
+                    format!("Zone radius: {:.0}", zone.radius),
+                    Style::default().fg(Color::LightMagenta),
+                )));
+                if player.alive && !zone.contains(player.pos) {
+                    lines.push(Line::from(Span::styled(
+                        "OUTSIDE THE ZONE!",
+                        Style::default().fg(Color::Red),
+                    )));
+                } else if !player.alive && player.respawn_timer.is_none() {
+                    lines.push(Line::from(Span::styled(
+                        "Eliminated - spectating",
+                        Style::default().fg(Color::Red),
+                    )));
+                }
+            }
 
             // Kill streak
             if player.kill_streak > 0 {
@@ -744,6 +1615,18 @@ fn render_scoreboard(state: &ClientState) -> Paragraph<'static> {
         }
     }
 
+    if !state.teams.is_empty() {
+        lines.push(Line::from(""));
+        let mut teams = state.teams.clone();
+        teams.sort_by_key(|t| std::cmp::Reverse(t.score));
+        for t in teams {
+            lines.push(Line::from(Span::styled(
+                format!("{} team: {}", team_name(t.team), t.score),
+                Style::default().fg(team_color(t.team)),
+            )));
+        }
+    }
+
     lines.push(Line::from(""));
     lines.push(Line::from(Span::styled(
         "Leaderboard",
@@ -754,24 +1637,142 @@ fn render_scoreboard(state: &ClientState) -> Paragraph<'static> {
     players.sort_by_key(|p| std::cmp::Reverse(p.score));
 
     for p in players.into_iter().take(5) {
-        let marker = if Some(p.id) == state.id { ">" } else { " " };
+        let marker = if Some(p.id) == state.viewed_id() { ">" } else { " " };
         let status = if !p.alive { " [dead]" } else { "" };
-        lines.push(Line::from(format!(
-            "{marker}{} ({}){status}",
-            truncate_name(&p.name, 8),
-            p.score
-        )));
+        let text = format!("{marker}{} ({}){status}", truncate_name(&p.name, 8), p.score);
+        match p.team {
+            Some(team) => lines.push(Line::from(Span::styled(
+                text,
+                Style::default().fg(team_color(team)),
+            ))),
+            None => lines.push(Line::from(text)),
+        }
     }
 
     lines.push(Line::from(""));
     lines.push(Line::from("wasd/arrows space"));
-    lines.push(Line::from("c:chat q:quit"));
+    lines.push(Line::from("c:chat l:lobby q:quit"));
 
     Paragraph::new(lines)
         .block(Block::default().borders(Borders::ALL).title("Info"))
         .wrap(Wrap { trim: true })
 }
 
+fn render_results(results: &MatchResults, state: &ClientState, block: Block<'static>) -> Paragraph<'static> {
+    let mut lines = vec![
+        Line::from(Span::styled("MATCH OVER", Style::default().fg(Color::Yellow))),
+        Line::from(""),
+    ];
+
+    if let Some(ref coop) = results.coop {
+        lines.push(Line::from(format!("Reached wave {}", results.wave)));
+        lines.push(Line::from(format!("Combined score: {}", coop.score)));
+        lines.push(Line::from(""));
+    }
+
+    let mut teams = results.teams.clone();
+    teams.sort_by_key(|t| std::cmp::Reverse(t.score));
+    let battle_royale = state.room.as_ref().is_some_and(|r| r.mode == GameMode::BattleRoyale);
+    if let Some(winner) = results.standings.first().filter(|_| battle_royale) {
+        lines.push(Line::from(Span::styled(
+            format!("{} wins!", winner.name),
+            Style::default().fg(Color::Yellow),
+        )));
+        lines.push(Line::from(""));
+    }
+
+    if let Some(winner) = teams.first() {
+        let text = if teams.get(1).is_some_and(|t| t.score == winner.score) {
+            "It's a draw!".to_string()
+        } else {
+            format!("{} team wins!", team_name(winner.team))
+        };
+        lines.push(Line::from(Span::styled(
+            text,
+            Style::default().fg(team_color(winner.team)),
+        )));
+        for t in &teams {
+            lines.push(Line::from(Span::styled(
+                format!("{} team: {}", team_name(t.team), t.score),
+                Style::default().fg(team_color(t.team)),
+            )));
+        }
+        lines.push(Line::from(""));
+    }
+
+    for (i, standing) in results.standings.iter().enumerate() {
+        let marker = if Some(standing.id) == state.viewed_id() { ">" } else { " " };
+        let text = format!("{marker}{:>2}. {:<16} {}", i + 1, standing.name, standing.score);
+        let style = match standing.team {
+            Some(team) => Style::default().fg(team_color(team)),
+            None if i == 0 => Style::default().fg(Color::Yellow),
+            None => Style::default(),
+        };
+        lines.push(Line::from(Span::styled(text, style)));
+    }
+
+    if let Some(info) = &state.match_info {
+        let t = match info.phase {
+            MatchPhase::Ended => info.countdown.map(|t| t + INTERMISSION_SECS),
+            _ => info.countdown,
+        };
+        if let Some(t) = t {
+            lines.push(Line::from(""));
+            lines.push(Line::from(format!("Next warmup in {t:.0}s")));
+        }
+    }
+
+    Paragraph::new(lines).block(block).wrap(Wrap { trim: false })
+}
+
+fn team_name(team: u8) -> &'static str {
+    match team {
+        0 => "Red",
+        1 => "Blue",
+        _ => "Other",
+    }
+}
+
+fn team_color(team: u8) -> Color {
+    match team {
+        0 => Color::LightRed,
+        1 => Color::LightBlue,
+        _ => Color::Gray,
+    }
+}
+
+/// Width of the health bar in the scoreboard, in cells.
+const HEALTH_BAR_WIDTH: usize = 10;
+
+/// `HP [#######---] 70`, coloured by how hurt the ship is, plus what is
+/// left of its shield.
+fn health_bar(health: f32, shield: f32) -> Line<'static> {
+    let fraction = health / MAX_HEALTH;
+    let color = if fraction > 0.6 {
+        Color::Green
+    } else if fraction > 0.3 {
+        Color::Yellow
+    } else {
+        Color::Red
+    };
+    let mut spans = vec![Span::raw("HP [")];
+    spans.extend(meter(fraction, HEALTH_BAR_WIDTH, color));
+    spans.push(Span::raw(format!("] {:.0}", health.ceil())));
+    if shield > 0.0 {
+        spans.push(Span::styled(format!(" +{:.0}", shield.ceil()), Style::default().fg(Color::Cyan)));
+    }
+    Line::from(spans)
+}
+
+/// `width` cells, filled with `#` up to `fraction` and `-` after that.
+fn meter(fraction: f32, width: usize, color: Color) -> [Span<'static>; 2] {
+    let filled = ((fraction.clamp(0.0, 1.0) * width as f32).ceil() as usize).min(width);
+    [
+        Span::styled("#".repeat(filled), Style::default().fg(color)),
+        Span::styled("-".repeat(width - filled), Style::default().fg(Color::DarkGray)),
+    ]
+}
+
 fn truncate_name(name: &str, max_len: usize) -> String {
     if name.len() <= max_len {
         name.to_string()
@@ -780,6 +1781,53 @@ fn truncate_name(name: &str, max_len: usize) -> String {
     }
 }
 
+fn render_lobby(state: &ClientState) -> Paragraph<'static> {
+    let mut lines = vec![
+        Line::from(Span::styled(
+            "Pick a room to play in",
+            Style::default().fg(Color::Yellow),
+        )),
+        Line::from(""),
+    ];
+
+    if state.rooms.is_empty() {
+        lines.push(Line::from("(no rooms yet)"));
+    }
+    for (i, room) in state.rooms.iter().enumerate() {
+        let text = format!(
+            "{} {} ({}/{}) - {}",
+            if i == state.selected_room { ">" } else { " " },
+            room.name,
+            room.players,
+            room.max_players,
+            room.mode
+        );
+        let style = if i == state.selected_room {
+            Style::default().fg(Color::Green)
+        } else {
+            Style::default()
+        };
+        lines.push(Line::from(Span::styled(text, style)));
+    }
+
+    lines.push(Line::from(""));
+    lines.push(Line::from(format!(
+        "New rooms: {} (m to change)",
+        NEW_ROOM_MODES[state.new_room_mode]
+    )));
+    lines.push(Line::from(""));
+    lines.push(Line::from(
+        "w/s or arrows: select  enter: join  n: new room  r: refresh  c: chat  q: quit",
+    ));
+    lines.push(Line::from(
+        "p: new private room  j: join private room  i: join with invite code",
+    ));
+
+    Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title("Lobby"))
+        .wrap(Wrap { trim: true })
+}
+
 fn render_chat(area: Rect, state: &ClientState) -> Paragraph<'static> {
     let block = Block::default().borders(Borders::ALL).title("Chat");
     let inner = block.inner(area);
@@ -791,9 +1839,14 @@ fn render_chat(area: Rect, state: &ClientState) -> Paragraph<'static> {
         .map(|l| Line::from(l.clone()))
         .collect::<Vec<_>>();
 
-    let prompt = match state.mode {
+    let prompt = match &state.mode {
+        _ if state.replay.is_some() => replay_status_line(state),
         Mode::Chat => format!("> {}", state.chat_input),
-        Mode::Game => "> (press c to chat)".to_string(),
+        Mode::Prompt(prompt) if prompt.is_secret() => {
+            format!("{}> {}", prompt.label(), "*".repeat(state.chat_input.chars().count()))
+        }
+        Mode::Prompt(prompt) => format!("{}> {}", prompt.label(), state.chat_input),
+        Mode::Game | Mode::Lobby => "> (press c to chat)".to_string(),
     };
     lines.push(Line::from(prompt));
 
@@ -802,15 +1855,85 @@ fn render_chat(area: Rect, state: &ClientState) -> Paragraph<'static> {
         .wrap(Wrap { trim: false })
 }
 
-fn parse_args() -> (String, String) {
+/// Playback position and controls, in place of the chat prompt while watching
+/// a replay.
+fn replay_status_line(state: &ClientState) -> String {
+    let Some(status) = &state.replay else {
+        return String::new();
+    };
+    let clock = |secs: f64| {
+        let secs = secs.max(0.0) as u64;
+        format!("{}:{:02}", secs / 60, secs % 60)
+    };
+    let watching = match state.camera {
+        Camera::Follow(id) => match state.players.get(&id) {
+            Some(player) => format!("following {}", player.name),
+            None => "followed player has left".to_string(),
+        },
+        Camera::Own | Camera::Free(_) => "free camera".to_string(),
+    };
+    format!(
+        "{} {} / {} at {}x, {}  |  space: pause  left/right: seek {}s  +/-: speed  tab: follow next  f/wasd: free camera  q: quit",
+        if status.paused { "paused" } else { "playing" },
+        clock(status.position),
+        clock(status.length),
+        status.speed,
+        watching,
+        REPLAY_SEEK_SECS,
+    )
+}
+
+/// How far left and right seek in a replay, in seconds.
+const REPLAY_SEEK_SECS: f64 = 5.0;
+
+/// Offline rooms are filled with bots up to this many players by default.
+const OFFLINE_MIN_PLAYERS: usize = 4;
+
+struct Args {
+    addr: String,
+    name: String,
+    encoding: Encoding,
+    /// Ask the server to move snapshots and inputs onto UDP.
+    udp: bool,
+    /// Run the server in-process instead of connecting to one.
+    offline: bool,
+    /// Bots for the in-process server.
+    bots: BotConfig,
+    /// Replay file to watch instead of playing.
+    replay: Option<PathBuf>,
+}
+
+fn parse_args() -> Args {
     let mut addr = "149.56.242.231:4000".to_string();
     let mut name: Option<String> = None;
+    let mut encoding = Encoding::Binary;
+    let mut udp = false;
+    let mut offline = false;
+    let mut replay = None;
+    let mut bots = BotConfig {
+        min_players: OFFLINE_MIN_PLAYERS,
+        ..BotConfig::default()
+    };
 
     for arg in std::env::args().skip(1) {
         if let Some(v) = arg.strip_prefix("--addr=") {
             addr = v.to_string();
         } else if let Some(v) = arg.strip_prefix("--name=") {
             name = Some(v.to_string());
+        } else if arg == "--json" {
+            encoding = Encoding::Json;
+        } else if arg == "--udp" {
+            udp = true;
+        } else if arg == "--offline" {
+            offline = true;
+        } else if let Some(v) = arg.strip_prefix("--replay=") {
+            replay = Some(PathBuf::from(v));
+        } else if let Some(v) = arg.strip_prefix("--bots=") {
+            bots.min_players = v.parse().unwrap_or(0);
+        } else if let Some(v) = arg.strip_prefix("--bot-difficulty=") {
+            if let Ok(difficulty) = v.parse() {
+                bots.difficulty = difficulty;
+            }
         }
     }
 
@@ -818,9 +1941,21 @@ fn parse_args() -> (String, String) {
         addr = v;
     }
 
-    let name = name.unwrap_or_else(|| prompt_for_name());
+    // Nobody plays in a replay, so there is no name to ask for
+    let name = match replay {
+        Some(_) => name.unwrap_or_default(),
+        None => name.unwrap_or_else(prompt_for_name),
+    };
 
-    (addr, name)
+    Args {
+        addr,
+        name,
+        encoding,
+        udp,
+        offline,
+        bots,
+        replay,
+    }
 }
 
 fn prompt_for_name() -> String {
@@ -0,0 +1,194 @@
+//! Playing back recorded matches (see [`shared::replay`]) on their own clock,
+//! which can be paused, sped up, slowed down and moved to any point.
+
+use std::time::Duration;
+
+use shared::sim::TICK_DT;
+use shared::snapshot::Snapshot;
+use shared::ServerMsg;
+
+/// Playback speeds, stepped through with `-` and `+`.
+const SPEEDS: [f64; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+/// Index of 1x in [`SPEEDS`].
+const NORMAL_SPEED: usize = 2;
+
+/// Where playback stands, for display.
+pub struct Status {
+    /// Seconds since the start of the recording.
+    pub position: f64,
+    /// Length of the recording in seconds.
+    pub length: f64,
+    pub speed: f64,
+    pub paused: bool,
+}
+
+pub struct Playback {
+    msgs: Vec<ServerMsg>,
+    /// Tick and index into `msgs` of every full state, in order.
+    keyframes: Vec<(u64, usize)>,
+    last_tick: u64,
+    /// Next message to play.
+    cursor: usize,
+    /// Last state played, which the next delta applies to.
+    current: Option<Snapshot>,
+    /// Playback position in server simulation time, in seconds.
+    time: f64,
+    /// Index into [`SPEEDS`].
+    speed: usize,
+    paused: bool,
+}
+
+fn tick_time(tick: u64) -> f64 {
+    tick as f64 * TICK_DT as f64
+}
+
+/// Tick of a state or delta; other messages have none.
+fn msg_tick(msg: &ServerMsg) -> Option<u64> {
+    match msg {
+        ServerMsg::State(snapshot) => Some(snapshot.tick),
+        ServerMsg::Delta(delta) => Some(delta.tick),
+        _ => None,
+    }
+}
+
+impl Playback {
+    /// Plays the messages of a replay from its first keyframe, or returns
+    /// `None` when it has none.
+    pub fn new(msgs: Vec<ServerMsg>) -> Option<Self> {
+        let keyframes: Vec<(u64, usize)> = msgs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, msg)| match msg {
+                ServerMsg::State(snapshot) => Some((snapshot.tick, i)),
+                _ => None,
+            })
+            .collect();
+        let first_tick = keyframes.first()?.0;
+        let last_tick = msgs.iter().filter_map(msg_tick).max().unwrap_or(first_tick);
+        Some(Self {
+            msgs,
+            keyframes,
+            last_tick,
+            cursor: 0,
+            current: None,
+            time: tick_time(first_tick),
+            speed: NORMAL_SPEED,
+            paused: false,
+        })
+    }
+
+    fn start_time(&self) -> f64 {
+        tick_time(self.keyframes[0].0)
+    }
+
+    fn end_time(&self) -> f64 {
+        tick_time(self.last_tick)
+    }
+
+    /// Playback position in server simulation time, to render at.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            position: self.time - self.start_time(),
+            length: self.end_time() - self.start_time(),
+            speed: SPEEDS[self.speed],
+            paused: self.paused,
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = self.speed.saturating_sub(1);
+    }
+
+    /// Moves playback on by `elapsed` of real time at the current speed and
+    /// returns the messages reached, with deltas resolved into full states.
+    /// The state just after the new position is included so there is always
+    /// something to interpolate towards.
+    pub fn advance(&mut self, elapsed: Duration) -> Vec<ServerMsg> {
+        if !self.paused {
+            let time = self.time + elapsed.as_secs_f64() * SPEEDS[self.speed];
+            self.time = time.min(self.end_time());
+        }
+        self.play(true)
+    }
+
+    /// Jumps to `offset` seconds from the current position, replaying from
+    /// the keyframe before it. Returns the messages to start over from, like
+    /// [`Playback::advance`]; chat and system messages on the way there are
+    /// left out, but the results of the last finished match are kept.
+    pub fn seek(&mut self, offset: f64) -> Vec<ServerMsg> {
+        self.time = (self.time + offset).clamp(self.start_time(), self.end_time());
+        let target = (self.time / TICK_DT as f64) as u64;
+        let keyframe = self
+            .keyframes
+            .iter()
+            .rev()
+            .find(|&&(tick, _)| tick <= target)
+            .unwrap_or(&self.keyframes[0]);
+        self.cursor = keyframe.1;
+        self.current = None;
+
+        let results = self.msgs[..self.cursor]
+            .iter()
+            .rev()
+            .find(|msg| matches!(msg, ServerMsg::MatchEnded { .. }))
+            .cloned();
+        let mut msgs: Vec<ServerMsg> = results.into_iter().collect();
+        msgs.extend(self.play(false));
+        msgs
+    }
+
+    /// Plays messages up to and including the first state after the current
+    /// position.
+    fn play(&mut self, events: bool) -> Vec<ServerMsg> {
+        let mut out = Vec::new();
+        while let Some(msg) = self.msgs.get(self.cursor) {
+            if msg_tick(msg).is_some()
+                && self
+                    .current
+                    .as_ref()
+                    .is_some_and(|current| tick_time(current.tick) > self.time)
+            {
+                break;
+            }
+            self.cursor += 1;
+            match msg {
+                ServerMsg::State(snapshot) => {
+                    self.current = Some(snapshot.clone());
+                }
+                ServerMsg::Delta(delta) => {
+                    // Deltas before the first keyframe have nothing to apply to
+                    let Some(snapshot) = self
+                        .current
+                        .as_ref()
+                        .filter(|current| current.tick == delta.base_tick)
+                        .and_then(|current| current.apply(delta))
+                    else {
+                        continue;
+                    };
+                    self.current = Some(snapshot);
+                }
+                msg if events => {
+                    out.push(msg.clone());
+                    continue;
+                }
+                _ => continue,
+            }
+            if let Some(current) = &self.current {
+                out.push(ServerMsg::State(current.clone()));
+            }
+        }
+        out
+    }
+}
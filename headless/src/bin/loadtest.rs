@@ -0,0 +1,245 @@
+//! Puts a server under load: connects a number of scripted clients, has them
+//! fly around and shoot for a while, then reports how steadily states arrived
+//! and how much traffic each client cost.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use headless::{Client, ConnectOptions, Event};
+use shared::codec::Encoding;
+use shared::sim::{TICK_DT, TICK_HZ};
+use shared::{GameMode, PlayerInput, ServerMsg};
+
+/// Scripted ships keep a heading for about this long.
+const TURN_INTERVAL_SECS: f32 = 1.0;
+
+struct Args {
+    addr: String,
+    clients: usize,
+    /// Clients are split into rooms of this many.
+    per_room: usize,
+    duration: Duration,
+    encoding: Encoding,
+    udp: bool,
+}
+
+/// What one client saw.
+#[derive(Default)]
+struct Report {
+    /// Time between consecutive states, in seconds.
+    intervals: Vec<f32>,
+    states: u64,
+    /// Ticks the server stepped past without us seeing their state.
+    missed_ticks: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    elapsed: Duration,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args();
+    let rooms = (0..args.clients).step_by(args.per_room).len();
+    println!(
+        "connecting {} clients in {rooms} rooms to {}",
+        args.clients, args.addr
+    );
+
+    let mut tasks = Vec::new();
+    for (room, first) in (0..args.clients).step_by(args.per_room).enumerate() {
+        let mut leader = Client::connect(&options(&args, room, 0)).await?;
+        reach_lobby(&mut leader).await?;
+        leader.create_room(&format!("loadtest {room}"), GameMode::FreeForAll);
+        let room_id = joined_room(&mut leader).await?;
+        tasks.push(tokio::spawn(play(leader, args.duration)));
+
+        let members = args.per_room.min(args.clients - first);
+        for member in 1..members {
+            let mut client = Client::connect(&options(&args, room, member)).await?;
+            reach_lobby(&mut client).await?;
+            client.join_room(room_id);
+            joined_room(&mut client).await?;
+            tasks.push(tokio::spawn(play(client, args.duration)));
+        }
+    }
+    println!(
+        "all clients joined; running for {}s",
+        args.duration.as_secs()
+    );
+
+    let mut reports = Vec::new();
+    for task in tasks {
+        reports.push(task.await?);
+    }
+    print_summary(&args, &reports);
+    Ok(())
+}
+
+fn options(args: &Args, room: usize, member: usize) -> ConnectOptions {
+    ConnectOptions {
+        addr: args.addr.clone(),
+        name: format!("load-{room}-{member}"),
+        encoding: args.encoding,
+        udp: args.udp,
+    }
+}
+
+/// Waits for the room list that greets a new client in the lobby.
+async fn reach_lobby(client: &mut Client) -> Result<()> {
+    loop {
+        match client.next_event().await {
+            Some(Event::Message(ServerMsg::RoomList { .. })) => return Ok(()),
+            Some(_) => {}
+            None => anyhow::bail!("server closed the connection"),
+        }
+    }
+}
+
+/// Waits until `client` is in the room it asked for and returns its id.
+async fn joined_room(client: &mut Client) -> Result<u64> {
+    loop {
+        match client.next_event().await {
+            Some(Event::Message(ServerMsg::RoomJoined { room })) => return Ok(room.id),
+            // Failures to create or join a room come back as system messages
+            Some(Event::Message(ServerMsg::System { text })) => {
+                anyhow::bail!("could not get into a room: {text}");
+            }
+            Some(_) => {}
+            None => anyhow::bail!("server closed the connection"),
+        }
+    }
+}
+
+/// Flies `client` around at random for `duration`, sending one input a tick
+/// like a real client.
+async fn play(mut client: Client, duration: Duration) -> Report {
+    let mut report = Report::default();
+    let start = Instant::now();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    let mut input_tick = tokio::time::interval(Duration::from_secs_f32(TICK_DT));
+    let mut rng = StdRng::from_entropy();
+    let mut input = PlayerInput::default();
+    let mut last_state: Option<(u64, Instant)> = None;
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = client.next_event() => {
+                let Some(event) = event else {
+                    break;
+                };
+                if let Event::State(snapshot) = event {
+                    let now = Instant::now();
+                    report.states += 1;
+                    if let Some((tick, at)) = last_state {
+                        report.intervals.push((now - at).as_secs_f32());
+                        report.missed_ticks += snapshot.tick.saturating_sub(tick + 1);
+                    }
+                    last_state = Some((snapshot.tick, now));
+                }
+            }
+            _ = input_tick.tick() => {
+                if rng.gen::<f32>() < TICK_DT / TURN_INTERVAL_SECS {
+                    let sector = rng.gen_range(0..8) as f32;
+                    input.target_angle = Some(sector * std::f32::consts::FRAC_PI_4);
+                    input.thrust = rng.gen_bool(0.7);
+                }
+                input.fire = rng.gen_bool(0.5);
+                input.seq += 1;
+                client.send_input(input.clone());
+            }
+        }
+    }
+
+    report.elapsed = start.elapsed();
+    report.bytes_sent = client.traffic().sent();
+    report.bytes_received = client.traffic().received();
+    report
+}
+
+fn print_summary(args: &Args, reports: &[Report]) {
+    let mut intervals: Vec<f32> = reports
+        .iter()
+        .flat_map(|r| r.intervals.iter().copied())
+        .collect();
+    intervals.sort_by(f32::total_cmp);
+    let states: u64 = reports.iter().map(|r| r.states).sum();
+    let missed: u64 = reports.iter().map(|r| r.missed_ticks).sum();
+    let secs: f32 = reports.iter().map(|r| r.elapsed.as_secs_f32()).sum();
+    let sent: u64 = reports.iter().map(|r| r.bytes_sent).sum();
+    let received: u64 = reports.iter().map(|r| r.bytes_received).sum();
+
+    println!();
+    println!("clients:       {}", reports.len());
+    println!(
+        "states:        {:.1}/s per client (server ticks at {TICK_HZ}/s), {:.2}% of ticks missed",
+        states as f32 / secs,
+        100.0 * missed as f32 / (states + missed).max(1) as f32
+    );
+    if !intervals.is_empty() {
+        let mean = intervals.iter().sum::<f32>() / intervals.len() as f32;
+        let variance =
+            intervals.iter().map(|i| (i - mean).powi(2)).sum::<f32>() / intervals.len() as f32;
+        let percentile = |p: f32| intervals[((intervals.len() - 1) as f32 * p) as usize];
+        println!(
+            "tick interval: mean {:.1} ms, jitter (std dev) {:.1} ms, p50 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+            mean * 1000.0,
+            variance.sqrt() * 1000.0,
+            percentile(0.5) * 1000.0,
+            percentile(0.99) * 1000.0,
+            intervals[intervals.len() - 1] * 1000.0
+        );
+    }
+    let per_client = |bytes: u64| bytes as f32 / secs / 1024.0;
+    println!(
+        "bandwidth:     {:.1} KiB/s down, {:.1} KiB/s up per client; {:.1} KiB/s down, {:.1} KiB/s up in total",
+        per_client(received),
+        per_client(sent),
+        per_client(received) * reports.len() as f32,
+        per_client(sent) * reports.len() as f32
+    );
+    println!(
+        "transport:     {} over {}",
+        match args.encoding {
+            Encoding::Binary => "binary",
+            Encoding::Json => "JSON",
+        },
+        if args.udp { "UDP" } else { "TCP" }
+    );
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        addr: "127.0.0.1:4000".to_string(),
+        clients: 10,
+        per_room: 8,
+        duration: Duration::from_secs(30),
+        encoding: Encoding::Binary,
+        udp: false,
+    };
+    for arg in std::env::args().skip(1) {
+        if let Some(v) = arg.strip_prefix("--addr=") {
+            args.addr = v.to_string();
+        } else if let Some(v) = arg.strip_prefix("--clients=") {
+            args.clients = v.parse().unwrap_or(args.clients);
+        } else if let Some(v) = arg.strip_prefix("--per-room=") {
+            args.per_room = v.parse().ok().filter(|&n| n > 0).unwrap_or(args.per_room);
+        } else if let Some(v) = arg.strip_prefix("--duration=") {
+            if let Ok(secs) = v.parse() {
+                args.duration = Duration::from_secs(secs);
+            }
+        } else if arg == "--json" {
+            args.encoding = Encoding::Json;
+        } else if arg == "--udp" {
+            args.udp = true;
+        }
+    }
+    if let Ok(v) = std::env::var("ASTEROIDS_ADDR") {
+        args.addr = v;
+    }
+    args
+}
@@ -0,0 +1,533 @@
+//! A game client without a user interface.
+//!
+//! [`connect`] does the networking: it runs the handshake and hands back
+//! channels of [`ClientMsg`]s and [`ServerMsg`]s, whatever the encoding and
+//! transport. The TUI client is built on it. [`Client`] goes one step further
+//! for scripts and load tests: it answers pings, resolves deltas into full
+//! snapshots and acknowledges them, so all that is left is deciding what to
+//! send.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+use shared::codec::{self, Encoding};
+use shared::snapshot::Snapshot;
+use shared::udp::{ClientDatagram, ServerDatagram, INPUT_REDUNDANCY, MAX_DATAGRAM_LEN};
+use shared::{
+    Capabilities, ClientMsg, GameMode, PlayerInput, RoomInfo, ServerMsg, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+
+/// Snapshots kept as possible bases for the server's deltas.
+const SNAPSHOT_HISTORY: usize = 32;
+
+/// How to reach a server and what to ask it for.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub addr: String,
+    pub name: String,
+    pub encoding: Encoding,
+    /// Ask the server to move snapshots and inputs onto UDP.
+    pub udp: bool,
+}
+
+/// Bytes a connection has moved so far, counted on the wire over both
+/// transports.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+    datagrams_received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Messages that came over UDP rather than the stream.
+    pub fn datagrams_received(&self) -> u64 {
+        self.datagrams_received.load(Ordering::Relaxed)
+    }
+
+    fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Both ends of a connection to a server.
+pub struct Connection {
+    pub tx: mpsc::UnboundedSender<ClientMsg>,
+    pub rx: mpsc::UnboundedReceiver<ServerMsg>,
+    pub traffic: Arc<Traffic>,
+}
+
+/// Connects and joins, then hands back channels for talking to the server.
+/// The handshake runs in JSON; once the server's `Welcome` confirms the
+/// encoding, the reader and writer tasks switch to it. If the server agreed to
+/// UDP, inputs and acks are sent as datagrams and snapshots may arrive either
+/// way; both end up on the same channel.
+pub async fn connect(options: &ConnectOptions) -> Result<Connection> {
+    let stream = TcpStream::connect(&options.addr).await?;
+    let server_addr = stream.peer_addr()?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let traffic = Arc::new(Traffic::default());
+
+    let mut capabilities = Capabilities::DELTA_SNAPSHOTS;
+    if options.encoding == Encoding::Binary {
+        capabilities = capabilities | Capabilities::BINARY_ENCODING;
+    }
+    if options.udp {
+        capabilities = capabilities | Capabilities::UDP_TRANSPORT;
+    }
+    let hello = ClientMsg::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities,
+    };
+    let join = ClientMsg::Join {
+        name: options.name.clone(),
+    };
+    for msg in [hello, join] {
+        let frame = codec::encode(Encoding::Json, &msg)?;
+        write_half.write_all(&frame).await?;
+        traffic.add_sent(frame.len());
+    }
+
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<ClientMsg>();
+    let (in_tx, in_rx) = mpsc::unbounded_channel::<ServerMsg>();
+
+    let (encoding, udp_token) = loop {
+        let Some(line) = read_frame(&mut reader, Encoding::Json, &traffic).await? else {
+            anyhow::bail!("server closed the connection during the handshake");
+        };
+        match codec::decode::<ServerMsg>(Encoding::Json, &line) {
+            Ok(ServerMsg::Rejected { reason }) => {
+                anyhow::bail!("server rejected the connection: {reason}");
+            }
+            Ok(ServerMsg::Welcome {
+                protocol_version, ..
+            }) if protocol_version < MIN_PROTOCOL_VERSION => {
+                anyhow::bail!(
+                    "server speaks protocol version {protocol_version}, this client needs at least {MIN_PROTOCOL_VERSION}"
+                );
+            }
+            Ok(
+                msg @ ServerMsg::Welcome {
+                    encoding,
+                    udp_token,
+                    ..
+                },
+            ) => {
+                let _ = in_tx.send(msg);
+                break (encoding, udp_token);
+            }
+            Ok(msg) => {
+                let _ = in_tx.send(msg);
+            }
+            Err(_) => {}
+        }
+    };
+
+    let udp = match udp_token {
+        Some(token) => Some((token, Arc::new(bind_udp(server_addr).await?))),
+        None => None,
+    };
+
+    // Id of the room we are in, 0 in the lobby. Datagrams sent by the room we
+    // just left can still arrive after `RoomLeft` on the TCP stream.
+    let current_room = Arc::new(AtomicU64::new(0));
+
+    let mut datagrams = udp.as_ref().map(|(token, socket)| {
+        DatagramSender::new(
+            *token,
+            Arc::clone(socket),
+            Arc::clone(&traffic),
+            Arc::clone(&current_room),
+        )
+    });
+    let writer_traffic = Arc::clone(&traffic);
+    tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            let msg = match datagrams.as_mut() {
+                Some(datagrams) => match datagrams.send(msg).await {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+                None => msg,
+            };
+            if let Ok(frame) = codec::encode(encoding, &msg) {
+                if write_half.write_all(&frame).await.is_err() {
+                    break;
+                }
+                writer_traffic.add_sent(frame.len());
+            }
+        }
+    });
+
+    if let Some((_, socket)) = udp {
+        let in_tx = in_tx.clone();
+        let current_room = Arc::clone(&current_room);
+        let traffic = Arc::clone(&traffic);
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_DATAGRAM_LEN];
+            let mut last_seq = 0;
+            loop {
+                let Ok(len) = socket.recv(&mut buf).await else {
+                    continue;
+                };
+                traffic.add_received(len);
+                let Ok(datagram) = codec::decode_datagram::<ServerDatagram>(&buf[..len]) else {
+                    continue;
+                };
+                // Drop duplicated and reordered datagrams
+                if datagram.seq <= last_seq {
+                    continue;
+                }
+                last_seq = datagram.seq;
+                if datagram.room_id != current_room.load(Ordering::Relaxed) {
+                    continue;
+                }
+                traffic.datagrams_received.fetch_add(1, Ordering::Relaxed);
+                if in_tx.send(datagram.msg).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let reader_traffic = Arc::clone(&traffic);
+    tokio::spawn(async move {
+        while let Ok(Some(frame)) = read_frame(&mut reader, encoding, &reader_traffic).await {
+            if let Ok(msg) = codec::decode::<ServerMsg>(encoding, &frame) {
+                match &msg {
+                    ServerMsg::RoomJoined { room } => {
+                        current_room.store(room.id, Ordering::Relaxed)
+                    }
+                    ServerMsg::RoomLeft => current_room.store(0, Ordering::Relaxed),
+                    _ => {}
+                }
+                let _ = in_tx.send(msg);
+            }
+        }
+    });
+
+    Ok(Connection {
+        tx: out_tx,
+        rx: in_rx,
+        traffic,
+    })
+}
+
+async fn bind_udp(server_addr: SocketAddr) -> io::Result<UdpSocket> {
+    let local = if server_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server_addr).await?;
+    Ok(socket)
+}
+
+/// Sends inputs and acks over UDP. Each datagram repeats the last few inputs
+/// so the server can recover from a lost one; acks ride along with the next
+/// input instead of costing a datagram of their own.
+struct DatagramSender {
+    socket: Arc<UdpSocket>,
+    traffic: Arc<Traffic>,
+    token: u64,
+    seq: u32,
+    /// Room we are in, as tracked by the reader.
+    current_room: Arc<AtomicU64>,
+    /// Room that `ack_tick` and `recent_inputs` belong to.
+    room: u64,
+    ack_tick: Option<u64>,
+    recent_inputs: VecDeque<PlayerInput>,
+}
+
+impl DatagramSender {
+    fn new(
+        token: u64,
+        socket: Arc<UdpSocket>,
+        traffic: Arc<Traffic>,
+        current_room: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            socket,
+            traffic,
+            token,
+            seq: 0,
+            current_room,
+            room: 0,
+            ack_tick: None,
+            recent_inputs: VecDeque::new(),
+        }
+    }
+
+    /// Takes `msg` if it belongs on UDP, or hands it back for the stream.
+    async fn send(&mut self, msg: ClientMsg) -> Option<ClientMsg> {
+        // Every room has its own ticks and input history, so acks and inputs
+        // meant for the last room must not reach the next
+        let room = self.current_room.load(Ordering::Relaxed);
+        if room != self.room {
+            self.room = room;
+            self.ack_tick = None;
+            self.recent_inputs.clear();
+        }
+        match msg {
+            ClientMsg::Ack { tick } => {
+                self.ack_tick = self.ack_tick.max(Some(tick));
+                return None;
+            }
+            ClientMsg::Input(input) if input.seq != 0 => {
+                self.recent_inputs.push_back(input);
+                while self.recent_inputs.len() > INPUT_REDUNDANCY {
+                    self.recent_inputs.pop_front();
+                }
+            }
+            // Inputs sent while the move is under way may already be routed
+            // to the next room; stop repeating the ack for this one
+            msg @ (ClientMsg::CreateRoom { .. }
+            | ClientMsg::JoinRoom { .. }
+            | ClientMsg::CreatePrivateRoom { .. }
+            | ClientMsg::JoinPrivateRoom { .. }
+            | ClientMsg::JoinByInvite { .. }) => {
+                self.ack_tick = None;
+                return Some(msg);
+            }
+            msg => return Some(msg),
+        }
+
+        self.seq += 1;
+        let datagram = ClientDatagram {
+            token: self.token,
+            seq: self.seq,
+            ack_tick: self.ack_tick,
+            inputs: self.recent_inputs.iter().cloned().collect(),
+        };
+        if let Ok(bytes) = codec::encode_datagram(&datagram) {
+            if self.socket.send(&bytes).await.is_ok() {
+                self.traffic.add_sent(bytes.len());
+            }
+        }
+        None
+    }
+}
+
+/// Reads one frame payload in `encoding`, or `None` at end of stream.
+async fn read_frame(
+    reader: &mut BufReader<OwnedReadHalf>,
+    encoding: Encoding,
+    traffic: &Traffic,
+) -> io::Result<Option<Vec<u8>>> {
+    match encoding {
+        Encoding::Json => {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(None);
+            }
+            traffic.add_received(line.len());
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            Ok(Some(line))
+        }
+        Encoding::Binary => {
+            let len = match reader.read_u32().await {
+                Ok(len) => len as usize,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            if len > codec::MAX_FRAME_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    codec::CodecError::FrameTooLarge(len),
+                ));
+            }
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload).await?;
+            traffic.add_received(4 + len);
+            Ok(Some(payload))
+        }
+    }
+}
+
+/// What a [`Client`] hands back from the server.
+#[derive(Debug)]
+pub enum Event {
+    /// The room's state at a new tick, with any delta already applied.
+    State(Snapshot),
+    /// Any other message.
+    Message(ServerMsg),
+}
+
+/// A connection that keeps up its side of the protocol on its own.
+pub struct Client {
+    tx: mpsc::UnboundedSender<ClientMsg>,
+    rx: mpsc::UnboundedReceiver<ServerMsg>,
+    traffic: Arc<Traffic>,
+    id: Option<u64>,
+    room: Option<RoomInfo>,
+    /// Set from asking to change rooms until the server answers. States that
+    /// arrive in the meantime are not acked, as the acks could reach the room
+    /// we are moving to.
+    switching_rooms: bool,
+    /// Recent states of the current room, oldest first.
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl Client {
+    pub async fn connect(options: &ConnectOptions) -> Result<Self> {
+        Ok(Self::from_connection(connect(options).await?))
+    }
+
+    /// Wraps an open connection, such as channels to a server running in the
+    /// same process.
+    pub fn from_connection(connection: Connection) -> Self {
+        Self {
+            tx: connection.tx,
+            rx: connection.rx,
+            traffic: connection.traffic,
+            id: None,
+            room: None,
+            switching_rooms: false,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Our player id, once the server has welcomed us.
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    pub fn room(&self) -> Option<&RoomInfo> {
+        self.room.as_ref()
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// Newest state of the current room.
+    pub fn state(&self) -> Option<&Snapshot> {
+        self.snapshots.back()
+    }
+
+    pub fn send(&mut self, msg: ClientMsg) {
+        if matches!(
+            msg,
+            ClientMsg::CreateRoom { .. }
+                | ClientMsg::JoinRoom { .. }
+                | ClientMsg::CreatePrivateRoom { .. }
+                | ClientMsg::JoinPrivateRoom { .. }
+                | ClientMsg::JoinByInvite { .. }
+        ) {
+            self.switching_rooms = true;
+        }
+        let _ = self.tx.send(msg);
+    }
+
+    pub fn send_input(&mut self, input: PlayerInput) {
+        self.send(ClientMsg::Input(input));
+    }
+
+    pub fn join_room(&mut self, room_id: u64) {
+        self.send(ClientMsg::JoinRoom { room_id });
+    }
+
+    pub fn create_room(&mut self, name: &str, mode: GameMode) {
+        self.send(ClientMsg::CreateRoom {
+            name: name.to_string(),
+            mode,
+        });
+    }
+
+    pub fn chat(&mut self, text: &str) {
+        self.send(ClientMsg::Chat {
+            text: text.to_string(),
+        });
+    }
+
+    /// Waits for the next thing worth looking at, or `None` once the server
+    /// has gone. States that arrive late or against a base we no longer hold
+    /// are skipped.
+    pub async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            let msg = self.rx.recv().await?;
+            match msg {
+                ServerMsg::State(snapshot) => {
+                    if let Some(snapshot) = self.accept(snapshot) {
+                        return Some(Event::State(snapshot));
+                    }
+                }
+                ServerMsg::Delta(delta) => {
+                    let snapshot = self
+                        .snapshots
+                        .iter()
+                        .find(|s| s.tick == delta.base_tick)
+                        .and_then(|base| base.apply(&delta));
+                    if let Some(snapshot) = snapshot.and_then(|s| self.accept(s)) {
+                        return Some(Event::State(snapshot));
+                    }
+                }
+                ServerMsg::Ping { nonce } => self.send(ClientMsg::Pong { nonce }),
+                msg => {
+                    match &msg {
+                        ServerMsg::Welcome { id, .. } => self.id = Some(*id),
+                        ServerMsg::RoomJoined { room } => {
+                            self.room = Some(room.clone());
+                            self.switching_rooms = false;
+                            self.snapshots.clear();
+                        }
+                        ServerMsg::RoomLeft => {
+                            self.room = None;
+                            self.switching_rooms = false;
+                            self.snapshots.clear();
+                        }
+                        // A room we could not get into is answered with a
+                        // notice, and we stay where we are
+                        ServerMsg::System { .. } => self.switching_rooms = false,
+                        _ => {}
+                    }
+                    return Some(Event::Message(msg));
+                }
+            }
+        }
+    }
+
+    /// Keeps and acknowledges `snapshot` if it is newer than what we hold.
+    fn accept(&mut self, snapshot: Snapshot) -> Option<Snapshot> {
+        if self.room.is_none() || self.state().is_some_and(|s| s.tick >= snapshot.tick) {
+            return None;
+        }
+        if !self.switching_rooms {
+            self.send(ClientMsg::Ack {
+                tick: snapshot.tick,
+            });
+        }
+        self.snapshots.push_back(snapshot.clone());
+        while self.snapshots.len() > SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+        Some(snapshot)
+    }
+}
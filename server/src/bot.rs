@@ -0,0 +1,212 @@
+//! Computer-controlled players that fill quiet rooms.
+//!
+//! Bots play through the same [`PlayerInput`]s as people. Every so often a
+//! bot looks at the world and picks one thing to do: get out of the way of an
+//! asteroid about to hit it, get back into the safe zone, grab a nearby
+//! power-up, or turn towards the nearest target and shoot. Between decisions
+//! it keeps doing the same thing, which is what makes slower bots slow.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use shared::sim::{asteroid_radius, secs_to_ticks, snap_angle, World, BULLET_SPEED, BULLET_TTL};
+use shared::{shortest_delta, GameMode, PlayerInput, Vec2, WORLD_HEIGHT, WORLD_WIDTH};
+
+/// Asteroids are dodged once they come this close to touching the ship.
+const DODGE_DISTANCE: f32 = 8.0;
+/// How far a bot goes out of its way for a power-up.
+const POWER_UP_RANGE: f32 = 25.0;
+/// Bots close in on targets further away than this, and hold still nearer.
+const ENGAGE_RANGE: f32 = 30.0;
+/// Bots only shoot at targets their bullets can reach.
+const FIRE_RANGE: f32 = BULLET_SPEED * BULLET_TTL * 0.6;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BotDifficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl BotDifficulty {
+    /// Time between a bot's decisions.
+    fn reaction_secs(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 0.6,
+            BotDifficulty::Normal => 0.3,
+            BotDifficulty::Hard => 0.1,
+        }
+    }
+
+    /// Largest error, in radians, in the direction a bot aims before it
+    /// snaps to one of the 8 directions ships fire in.
+    fn aim_error(self) -> f32 {
+        match self {
+            BotDifficulty::Easy => 0.6,
+            BotDifficulty::Normal => 0.3,
+            BotDifficulty::Hard => 0.1,
+        }
+    }
+}
+
+impl fmt::Display for BotDifficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotDifficulty::Easy => f.write_str("easy"),
+            BotDifficulty::Normal => f.write_str("normal"),
+            BotDifficulty::Hard => f.write_str("hard"),
+        }
+    }
+}
+
+impl FromStr for BotDifficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(BotDifficulty::Easy),
+            "normal" => Ok(BotDifficulty::Normal),
+            "hard" => Ok(BotDifficulty::Hard),
+            _ => Err(format!("unknown bot difficulty {s:?}")),
+        }
+    }
+}
+
+/// How rooms are topped up with bots.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BotConfig {
+    /// Rooms with anyone in them are filled with bots up to this many
+    /// players. Bots leave to make room as people join; 0 turns bots off.
+    pub min_players: usize,
+    pub difficulty: BotDifficulty,
+}
+
+pub struct Bot {
+    id: u64,
+    difficulty: BotDifficulty,
+    rng: ChaCha8Rng,
+    input: PlayerInput,
+    next_decision_at: u64,
+}
+
+impl Bot {
+    pub fn new(id: u64, difficulty: BotDifficulty, seed: u64) -> Self {
+        Self {
+            id,
+            difficulty,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            input: PlayerInput::default(),
+            next_decision_at: 0,
+        }
+    }
+
+    /// The bot's input for the next step of `world`.
+    pub fn input(&mut self, world: &World) -> PlayerInput {
+        if world.tick() >= self.next_decision_at {
+            self.next_decision_at = world.tick() + secs_to_ticks(self.difficulty.reaction_secs());
+            self.input = self.decide(world);
+        }
+        self.input.clone()
+    }
+
+    fn decide(&mut self, world: &World) -> PlayerInput {
+        let Some(me) = world.player(self.id).filter(|p| p.alive) else {
+            return PlayerInput::default();
+        };
+
+        // An asteroid on its way in, closest first
+        let threat = world
+            .asteroids()
+            .iter()
+            .filter_map(|asteroid| {
+                let offset = delta(me.pos, asteroid.pos);
+                let gap = length(offset) - asteroid_radius(asteroid.size);
+                let closing = dot(offset, asteroid.vel.add(me.vel.scale(-1.0))) < 0.0;
+                (gap < DODGE_DISTANCE && closing).then_some((gap, offset))
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some((_, offset)) = threat {
+            // Straight away from it
+            return move_towards(angle_of(offset.scale(-1.0)));
+        }
+
+        if let Some(zone) = world.safe_zone().filter(|zone| !zone.contains(me.pos)) {
+            return move_towards(angle_of(delta(me.pos, zone.center)));
+        }
+
+        let power_up = world
+            .power_ups()
+            .map(|power_up| delta(me.pos, power_up.pos))
+            .filter(|offset| length(*offset) < POWER_UP_RANGE)
+            .min_by(|a, b| length(*a).total_cmp(&length(*b)));
+        if let Some(offset) = power_up {
+            return move_towards(angle_of(offset));
+        }
+
+        let Some(target) = self.nearest_target(world, me.pos, me.team) else {
+            return PlayerInput::default();
+        };
+        let error = self.difficulty.aim_error();
+        let aim = angle_of(target) + self.rng.gen_range(-error..=error);
+        let distance = length(target);
+        PlayerInput {
+            thrust: distance > ENGAGE_RANGE,
+            target_angle: Some(snap_angle(aim)),
+            fire: distance < FIRE_RANGE,
+            seq: 0,
+        }
+    }
+
+    /// Offset to the closest thing worth shooting: asteroids, UFOs, the boss,
+    /// and other ships where they can be hurt.
+    fn nearest_target(&self, world: &World, pos: Vec2, team: Option<u8>) -> Option<Vec2> {
+        let pvp = world.mode() != GameMode::Coop;
+        let ships = world
+            .players()
+            .filter(|p| pvp && p.alive && p.id != self.id)
+            .filter(|p| team.is_none() || p.team != team)
+            .map(|p| p.pos);
+        world
+            .asteroids()
+            .iter()
+            .map(|asteroid| asteroid.pos)
+            .chain(world.ufos().map(|ufo| ufo.pos))
+            .chain(world.boss().map(|boss| boss.pos))
+            .chain(ships)
+            .map(|target| delta(pos, target))
+            .min_by(|a, b| length(*a).total_cmp(&length(*b)))
+    }
+}
+
+fn move_towards(angle: f32) -> PlayerInput {
+    PlayerInput {
+        thrust: true,
+        target_angle: Some(snap_angle(angle)),
+        fire: false,
+        seq: 0,
+    }
+}
+
+/// Shortest offset from `from` to `to` across the wrapping world.
+fn delta(from: Vec2, to: Vec2) -> Vec2 {
+    Vec2::new(
+        shortest_delta(to.x, from.x, WORLD_WIDTH),
+        shortest_delta(to.y, from.y, WORLD_HEIGHT),
+    )
+}
+
+fn angle_of(v: Vec2) -> f32 {
+    v.y.atan2(v.x)
+}
+
+fn length(v: Vec2) -> f32 {
+    (v.x * v.x + v.y * v.y).sqrt()
+}
+
+fn dot(a: Vec2, b: Vec2) -> f32 {
+    a.x * b.x + a.y * b.y
+}
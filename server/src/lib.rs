@@ -0,0 +1,46 @@
+//! The game server: a lobby of rooms, each ticking its own world.
+//!
+//! The `server` binary serves it over TCP and UDP; [`local::LocalServer`]
+//! runs it in-process for offline play.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use shared::sim::MatchRules;
+
+/// Off while the server runs in-process, since the console then belongs to
+/// the client.
+static LOGGING: AtomicBool = AtomicBool::new(true);
+
+/// Prints a server event to the console, unless logging is off.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if crate::logging() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub mod bot;
+pub mod lobby;
+pub mod local;
+pub mod record;
+pub mod room;
+pub mod session;
+
+const DEFAULT_TIME_LIMIT_SECS: u32 = 600;
+
+/// How matches end unless the server is told otherwise.
+pub fn default_rules() -> MatchRules {
+    MatchRules {
+        time_limit_secs: Some(DEFAULT_TIME_LIMIT_SECS),
+        score_limit: None,
+    }
+}
+
+fn logging() -> bool {
+    LOGGING.load(Ordering::Relaxed)
+}
+
+fn set_logging(on: bool) {
+    LOGGING.store(on, Ordering::Relaxed);
+}
@@ -0,0 +1,488 @@
+//! Connected clients and the rooms they can play in.
+//!
+//! Clients start out in the lobby, where they can list, create and join
+//! rooms. Each room runs its own world and tick loop; see [`Room`]. The lobby
+//! is always locked before a room, never the other way round.
+//!
+//! Private rooms are left out of the room list. They are entered with their
+//! invite code, or with their name and password if they have one.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use shared::sim::MatchRules;
+use shared::{Capabilities, GameMode, RoomInfo, ServerMsg};
+
+use crate::bot::BotConfig;
+use crate::room::{self, ClientConn, ClientTx, Room};
+
+/// Room every server starts with. It stays open when empty, so the lobby
+/// always has somewhere to play.
+pub const DEFAULT_ROOM_NAME: &str = "Public Arena";
+const MAX_ROOMS: usize = 32;
+const MAX_ROOM_NAME_LEN: usize = 24;
+const MAX_PASSWORD_LEN: usize = 32;
+const INVITE_CODE_LEN: usize = 6;
+/// No 0/O or 1/I, so codes survive being read out over voice chat.
+const INVITE_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub type SharedRoom = Arc<Mutex<Room>>;
+
+struct RoomEntry {
+    name: String,
+    room: SharedRoom,
+    tick_loop: JoinHandle<()>,
+    /// Kept open when the last player leaves.
+    permanent: bool,
+    access: Option<PrivateAccess>,
+}
+
+/// How to get into a private room.
+struct PrivateAccess {
+    invite_code: String,
+    password: Option<String>,
+}
+
+struct Session {
+    name: String,
+    tx: ClientTx,
+    room_id: Option<u64>,
+    /// Held here while the client is in the lobby; owned by the room while it
+    /// plays.
+    conn: Option<ClientConn>,
+}
+
+pub struct Lobby {
+    /// Seeds for new rooms, derived from the server seed.
+    rng: ChaCha8Rng,
+    /// How every room's matches end.
+    rules: MatchRules,
+    /// How every room is topped up with bots.
+    bots: BotConfig,
+    /// Where rooms record their matches, if they do.
+    record_dir: Option<PathBuf>,
+    /// Shared by every room's tick loop; `None` for an in-process server.
+    udp: Option<Arc<UdpSocket>>,
+    next_client_id: u64,
+    next_room_id: u64,
+    rooms: BTreeMap<u64, RoomEntry>,
+    sessions: HashMap<u64, Session>,
+    /// Client id for each UDP session token.
+    udp_tokens: HashMap<u64, u64>,
+}
+
+impl Lobby {
+    /// Creates the lobby and its default room, whose world uses `seed`.
+    pub fn new(
+        seed: u64,
+        rules: MatchRules,
+        bots: BotConfig,
+        record_dir: Option<PathBuf>,
+        udp: Option<Arc<UdpSocket>>,
+    ) -> Self {
+        let mut lobby = Self {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            rules,
+            bots,
+            record_dir,
+            udp,
+            next_client_id: 1,
+            next_room_id: 1,
+            rooms: BTreeMap::new(),
+            sessions: HashMap::new(),
+            udp_tokens: HashMap::new(),
+        };
+        lobby.open_room(
+            DEFAULT_ROOM_NAME.to_string(),
+            seed,
+            GameMode::FreeForAll,
+            true,
+            None,
+        );
+        lobby
+    }
+
+    /// Registers a client that finished the handshake. It starts in the
+    /// lobby. Returns its id and, if it negotiated UDP, its session token.
+    pub fn connect(
+        &mut self,
+        name: String,
+        tx: ClientTx,
+        capabilities: Capabilities,
+    ) -> (u64, Option<u64>) {
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+        let udp_token = capabilities
+            .contains(Capabilities::UDP_TRANSPORT)
+            .then(|| self.new_udp_token(id));
+        let conn = ClientConn::new(tx.clone(), capabilities, udp_token);
+        self.sessions.insert(
+            id,
+            Session {
+                name,
+                tx,
+                room_id: None,
+                conn: Some(conn),
+            },
+        );
+        (id, udp_token)
+    }
+
+    /// Takes the client out of its room, if any, and forgets it.
+    pub async fn disconnect(&mut self, id: u64) {
+        self.leave_room(id).await;
+        if let Some(token) = self
+            .sessions
+            .remove(&id)
+            .and_then(|s| s.conn)
+            .and_then(|c| c.udp)
+            .map(|peer| peer.token)
+        {
+            self.udp_tokens.remove(&token);
+        }
+    }
+
+    /// The room `id` is playing in.
+    pub fn room_of(&self, id: u64) -> Option<SharedRoom> {
+        let room_id = self.sessions.get(&id)?.room_id?;
+        self.rooms
+            .get(&room_id)
+            .map(|entry| Arc::clone(&entry.room))
+    }
+
+    /// The client a UDP session token belongs to, and its room if it is in one.
+    pub fn route_datagram(&self, token: u64) -> Option<(u64, SharedRoom)> {
+        let id = *self.udp_tokens.get(&token)?;
+        Some((id, self.room_of(id)?))
+    }
+
+    /// The public rooms.
+    pub async fn room_list(&self) -> Vec<RoomInfo> {
+        let mut rooms = Vec::with_capacity(self.rooms.len());
+        for entry in self.rooms.values().filter(|e| e.access.is_none()) {
+            rooms.push(entry.room.lock().await.info());
+        }
+        rooms
+    }
+
+    /// Sends `msg` to every client that is in the lobby rather than a room.
+    pub fn broadcast_lobby(&self, msg: ServerMsg) {
+        for session in self.sessions.values().filter(|s| s.room_id.is_none()) {
+            let _ = session.tx.send(msg.clone());
+        }
+    }
+
+    /// Opens a room called `name` and moves `id` into it.
+    pub async fn create_room(
+        &mut self,
+        id: u64,
+        name: &str,
+        mode: GameMode,
+    ) -> Result<SharedRoom, String> {
+        let name = self.check_new_room_name(name)?.to_string();
+        let seed = self.rng.gen();
+        let room_id = self.open_room(name.clone(), seed, mode, false, None);
+        log!("room {name} opened ({mode}, seed {seed})");
+        self.enter_room(id, room_id).await
+    }
+
+    /// Opens a private room called `name` and moves `id` into it. Everyone
+    /// who enters the room is told its invite code.
+    pub async fn create_private_room(
+        &mut self,
+        id: u64,
+        name: &str,
+        password: Option<&str>,
+        mode: GameMode,
+    ) -> Result<SharedRoom, String> {
+        let name = self.check_new_room_name(name)?.to_string();
+        let password = password.filter(|p| !p.is_empty());
+        if password.is_some_and(|p| p.chars().count() > MAX_PASSWORD_LEN) {
+            return Err(format!(
+                "Passwords can be at most {MAX_PASSWORD_LEN} characters long"
+            ));
+        }
+
+        let access = PrivateAccess {
+            invite_code: self.new_invite_code(),
+            password: password.map(str::to_string),
+        };
+        let seed = self.rng.gen();
+        let room_id = self.open_room(name.clone(), seed, mode, false, Some(access));
+        log!("private room {name} opened ({mode}, seed {seed})");
+        self.enter_room(id, room_id).await
+    }
+
+    /// Moves `id` into public room `room_id`, leaving its current room.
+    pub async fn join_room(&mut self, id: u64, room_id: u64) -> Result<SharedRoom, String> {
+        match self.rooms.get(&room_id) {
+            None => Err("That room no longer exists".to_string()),
+            Some(entry) if entry.access.is_some() => Err("That room is private".to_string()),
+            Some(_) => self.enter_room(id, room_id).await,
+        }
+    }
+
+    /// Moves `id` into the private room called `name` if `password` is its
+    /// password.
+    pub async fn join_private_room(
+        &mut self,
+        id: u64,
+        name: &str,
+        password: &str,
+    ) -> Result<SharedRoom, String> {
+        let name = name.trim();
+        let room_id = self.rooms.iter().find_map(|(room_id, entry)| {
+            let access = entry.access.as_ref()?;
+            (entry.name.eq_ignore_ascii_case(name) && access.password.as_deref() == Some(password))
+                .then_some(*room_id)
+        });
+        match room_id {
+            Some(room_id) => self.enter_room(id, room_id).await,
+            None => Err("Wrong room name or password".to_string()),
+        }
+    }
+
+    /// Moves `id` into the private room with invite code `code`.
+    pub async fn join_by_invite(&mut self, id: u64, code: &str) -> Result<SharedRoom, String> {
+        let code = code.trim().to_ascii_uppercase();
+        let room_id = self.rooms.iter().find_map(|(room_id, entry)| {
+            let access = entry.access.as_ref()?;
+            (access.invite_code == code).then_some(*room_id)
+        });
+        match room_id {
+            Some(room_id) => self.enter_room(id, room_id).await,
+            None => Err("Unknown invite code".to_string()),
+        }
+    }
+
+    /// Moves `id` into room `room_id`, leaving its current room. The client
+    /// is sent `RoomJoined` before any state of the new room.
+    async fn enter_room(&mut self, id: u64, room_id: u64) -> Result<SharedRoom, String> {
+        let Some(entry) = self.rooms.get(&room_id) else {
+            return Err("That room no longer exists".to_string());
+        };
+        let target = Arc::clone(&entry.room);
+        let invite_code = entry.access.as_ref().map(|a| a.invite_code.clone());
+        let Some(session) = self.sessions.get(&id) else {
+            return Err("Not connected".to_string());
+        };
+        if session.room_id == Some(room_id) {
+            return Err("You are already in that room".to_string());
+        }
+        if target.lock().await.is_full() {
+            return Err("That room is full".to_string());
+        }
+
+        self.leave_room(id).await;
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return Err("Not connected".to_string());
+        };
+        let Some(conn) = session.conn.take() else {
+            return Err("Not connected".to_string());
+        };
+        session.room_id = Some(room_id);
+
+        let mut room = target.lock().await;
+        room.add_client(id, session.name.clone(), conn);
+        let _ = session.tx.send(ServerMsg::RoomJoined { room: room.info() });
+        if let Some(code) = invite_code {
+            let _ = session.tx.send(ServerMsg::System {
+                text: format!("Invite code for this room: {code}"),
+            });
+        }
+        room.broadcast(ServerMsg::System {
+            text: format!("{} joined the room", session.name),
+        });
+        drop(room);
+        Ok(target)
+    }
+
+    /// Moves `id` back to the lobby. Rooms other than the default one close
+    /// when their last player leaves. Returns false if `id` was not in a room.
+    pub async fn leave_room(&mut self, id: u64) -> bool {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return false;
+        };
+        let Some(room_id) = session.room_id.take() else {
+            return false;
+        };
+        let Some(entry) = self.rooms.get(&room_id) else {
+            return false;
+        };
+
+        let mut room = entry.room.lock().await;
+        if let Some((conn, name)) = room.remove_client(id) {
+            session.conn = Some(conn);
+            if let Some(name) = name {
+                room.broadcast(ServerMsg::System {
+                    text: format!("{name} left the room"),
+                });
+            }
+        }
+        let close = room.is_empty() && !entry.permanent;
+        drop(room);
+
+        if close {
+            if let Some(entry) = self.rooms.remove(&room_id) {
+                entry.tick_loop.abort();
+                log!("room {} closed", entry.name);
+            }
+        }
+        true
+    }
+
+    /// Trims `name` and checks it can be used for a new room.
+    fn check_new_room_name<'a>(&self, name: &'a str) -> Result<&'a str, String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LEN {
+            return Err(format!(
+                "Room names must be 1 to {MAX_ROOM_NAME_LEN} characters long"
+            ));
+        }
+        if self
+            .rooms
+            .values()
+            .any(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            return Err(format!("A room called {name} already exists"));
+        }
+        if self.rooms.len() >= MAX_ROOMS {
+            return Err("The server has no room for more rooms".to_string());
+        }
+        Ok(name)
+    }
+
+    fn open_room(
+        &mut self,
+        name: String,
+        seed: u64,
+        mode: GameMode,
+        permanent: bool,
+        access: Option<PrivateAccess>,
+    ) -> u64 {
+        let room_id = self.next_room_id;
+        self.next_room_id += 1;
+        let room = Arc::new(Mutex::new(Room::new(
+            room_id,
+            name.clone(),
+            seed,
+            mode,
+            self.rules,
+            self.bots,
+            self.record_dir.clone(),
+        )));
+        let tick_loop = room::spawn_tick_loop(Arc::clone(&room), self.udp.clone());
+        self.rooms.insert(
+            room_id,
+            RoomEntry {
+                name,
+                room,
+                tick_loop,
+                permanent,
+                access,
+            },
+        );
+        room_id
+    }
+
+    /// Picks an unguessable invite code no open room uses. Not drawn from the
+    /// seeded rng, since the server seed is no secret.
+    fn new_invite_code(&self) -> String {
+        let mut rng = rand::thread_rng();
+        loop {
+            let code: String = (0..INVITE_CODE_LEN)
+                .map(|_| INVITE_CODE_ALPHABET[rng.gen_range(0..INVITE_CODE_ALPHABET.len())] as char)
+                .collect();
+            let taken = self
+                .rooms
+                .values()
+                .filter_map(|entry| entry.access.as_ref())
+                .any(|access| access.invite_code == code);
+            if !taken {
+                return code;
+            }
+        }
+    }
+
+    /// Picks an unguessable token for `id`'s UDP session.
+    fn new_udp_token(&mut self, id: u64) -> u64 {
+        loop {
+            let token = rand::random();
+            if let Entry::Vacant(entry) = self.udp_tokens.entry(token) {
+                entry.insert(id);
+                return token;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn connect(lobby: &mut Lobby, name: &str) -> (u64, mpsc::UnboundedReceiver<ServerMsg>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (id, _) = lobby.connect(name.into(), tx, Capabilities::default());
+        (id, rx)
+    }
+
+    /// The invite code the creator of a private room was told.
+    fn invite_code(rx: &mut mpsc::UnboundedReceiver<ServerMsg>) -> String {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .find_map(|msg| match msg {
+                ServerMsg::System { text } => text
+                    .strip_prefix("Invite code for this room: ")
+                    .map(str::to_string),
+                _ => None,
+            })
+            .expect("no invite code was sent")
+    }
+
+    #[tokio::test]
+    async fn private_rooms_admit_only_the_right_password_or_invite_code() {
+        let mut lobby = Lobby::new(1, MatchRules::default(), BotConfig::default(), None, None);
+        let (host, mut host_rx) = connect(&mut lobby, "host");
+        let (guest, _guest_rx) = connect(&mut lobby, "guest");
+        lobby
+            .create_private_room(host, "Hideout", Some("hunter2"), GameMode::FreeForAll)
+            .await
+            .unwrap();
+        let code = invite_code(&mut host_rx);
+        let wrong_code = if code == "AAAAAA" { "BBBBBB" } else { "AAAAAA" };
+
+        assert!(lobby
+            .join_private_room(guest, "Hideout", "hunter3")
+            .await
+            .is_err());
+        assert!(lobby
+            .join_private_room(guest, "Hideaway", "hunter2")
+            .await
+            .is_err());
+        assert!(lobby.join_by_invite(guest, wrong_code).await.is_err());
+        assert!(lobby.room_of(guest).is_none());
+
+        lobby
+            .join_private_room(guest, "hideout", "hunter2")
+            .await
+            .unwrap();
+        let room = lobby.room_of(guest).expect("guest is not in a room");
+        assert!(Arc::ptr_eq(&room, &lobby.room_of(host).unwrap()));
+
+        lobby.leave_room(guest).await;
+        lobby
+            .join_by_invite(guest, &code.to_ascii_lowercase())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&room, &lobby.room_of(guest).unwrap()));
+    }
+}
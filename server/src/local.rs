@@ -0,0 +1,73 @@
+//! A server running inside the client's process, for playing without a
+//! network.
+//!
+//! Clients talk to it over channels: messages are handed over as they are,
+//! with no encoding, handshake or UDP. Everything behind the channels is the
+//! real server, so rooms tick on their own tasks and sessions behave exactly
+//! like networked ones.
+
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, Mutex};
+
+use shared::codec::Encoding;
+use shared::sim::{MatchRules, TICK_HZ};
+use shared::{Capabilities, ClientMsg, ServerMsg, PROTOCOL_VERSION};
+
+use crate::bot::BotConfig;
+use crate::lobby::Lobby;
+use crate::session::ClientSession;
+
+pub struct LocalServer {
+    lobby: Arc<Mutex<Lobby>>,
+}
+
+impl LocalServer {
+    /// Starts the lobby and its default room on the current runtime. The
+    /// server's console messages are turned off, as the terminal belongs to
+    /// the client.
+    pub fn new(seed: u64, rules: MatchRules, bots: BotConfig) -> Self {
+        crate::set_logging(false);
+        Self {
+            lobby: Arc::new(Mutex::new(Lobby::new(seed, rules, bots, None, None))),
+        }
+    }
+
+    /// Connects a client called `name`. The first message on the returned
+    /// receiver is its `Welcome`; dropping the sender disconnects it.
+    pub async fn connect(
+        &self,
+        name: String,
+    ) -> (
+        mpsc::UnboundedSender<ClientMsg>,
+        mpsc::UnboundedReceiver<ServerMsg>,
+    ) {
+        let (client_tx, mut client_rx) = mpsc::unbounded_channel::<ClientMsg>();
+        let (tx, rx) = mpsc::unbounded_channel::<ServerMsg>();
+
+        // Nothing is encoded, so only the features above the transport apply
+        let capabilities = Capabilities::SUPPORTED
+            & !(Capabilities::BINARY_ENCODING | Capabilities::UDP_TRANSPORT);
+        let (mut session, _) =
+            ClientSession::connect(Arc::clone(&self.lobby), name, tx.clone(), capabilities).await;
+        let _ = tx.send(ServerMsg::Welcome {
+            id: session.id(),
+            tick_hz: TICK_HZ,
+            protocol_version: PROTOCOL_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities,
+            encoding: Encoding::Json,
+            udp_token: None,
+        });
+        session.start().await;
+
+        tokio::spawn(async move {
+            while let Some(msg) = client_rx.recv().await {
+                session.handle(msg).await;
+            }
+            session.disconnect().await;
+        });
+
+        (client_tx, rx)
+    }
+}
@@ -0,0 +1,113 @@
+//! Writing rooms' matches to replay files; see [`shared::replay`] for the
+//! format.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+
+use shared::replay::{self, ReplayHeader, KEYFRAME_INTERVAL_TICKS, REPLAY_FORMAT};
+use shared::sim::TICK_HZ;
+use shared::snapshot::Snapshot;
+use shared::{GameMode, ServerMsg, PROTOCOL_VERSION};
+
+/// One replay file being written. Frames are handed to a task that does the
+/// writing, so the tick loop never waits on the disk; the file is complete
+/// once the recorder is dropped and that task has drained.
+pub struct Recorder {
+    frames: mpsc::UnboundedSender<Vec<u8>>,
+    path: PathBuf,
+    /// Last state recorded, which the next delta is taken against.
+    last: Option<Snapshot>,
+    last_keyframe_tick: u64,
+}
+
+impl Recorder {
+    /// Starts a new replay of room `room` in `dir`. The room id goes into
+    /// the file name, since two rooms can share a name; an existing file is
+    /// never overwritten.
+    pub fn start(
+        dir: &Path,
+        room_id: u64,
+        room: &str,
+        seed: u64,
+        mode: GameMode,
+    ) -> std::io::Result<Self> {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let file_name: String = room
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let path = dir.join(format!("{file_name}-{room_id}-{started_at}.replay"));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        let header = ReplayHeader {
+            format: REPLAY_FORMAT.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            seed,
+            tick_hz: TICK_HZ,
+            room: room.to_string(),
+            mode,
+            started_at,
+        };
+        let header = replay::encode_header(&header)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        let (frames, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let task_path = path.clone();
+        tokio::spawn(async move {
+            let mut writer = BufWriter::new(tokio::fs::File::from_std(file));
+            let result = async {
+                writer.write_all(&header).await?;
+                while let Some(frame) = rx.recv().await {
+                    writer.write_all(&frame).await?;
+                }
+                writer.flush().await
+            }
+            .await;
+            if let Err(err) = result {
+                log!("failed to write replay {}: {err}", task_path.display());
+            }
+        });
+
+        Ok(Self {
+            frames,
+            path,
+            last: None,
+            last_keyframe_tick: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records the state of a new tick.
+    pub fn record_state(&mut self, snapshot: &Snapshot) {
+        let keyframe = snapshot.tick >= self.last_keyframe_tick + KEYFRAME_INTERVAL_TICKS;
+        let msg = match &self.last {
+            Some(last) if !keyframe => ServerMsg::Delta(Box::new(last.diff(snapshot))),
+            _ => {
+                self.last_keyframe_tick = snapshot.tick;
+                ServerMsg::State(snapshot.clone())
+            }
+        };
+        self.record(&msg);
+        self.last = Some(snapshot.clone());
+    }
+
+    pub fn record(&mut self, msg: &ServerMsg) {
+        match replay::encode_msg(msg) {
+            Ok(frame) => {
+                let _ = self.frames.send(frame);
+            }
+            Err(err) => log!("failed to encode replay frame: {err}"),
+        }
+    }
+}
@@ -0,0 +1,642 @@
+//! A single game room: its world, the clients playing in it and the tick loop
+//! that advances it.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use shared::codec;
+use shared::sim::{MatchRules, World, INTERPOLATION_DELAY_SECS, TICK_DT, TICK_HZ};
+use shared::snapshot::Snapshot;
+use shared::udp::{ClientDatagram, ServerDatagram};
+use shared::{Capabilities, GameMode, MatchPhase, PlayerInput, RoomInfo, ServerMsg};
+
+use crate::bot::{Bot, BotConfig};
+use crate::record::Recorder;
+
+/// Sequenced inputs buffered beyond this many ticks are dropped (oldest first)
+/// so a burst from a lagging client cannot delay every later input.
+const MAX_QUEUED_INPUTS: usize = 4;
+
+/// How many past snapshots are kept as possible delta baselines (1.6 s).
+const SNAPSHOT_HISTORY: usize = 32;
+/// A full state is sent at least this often, even to clients that ack.
+const KEYFRAME_INTERVAL_TICKS: u64 = 100;
+
+/// How often each client's round trip time is measured.
+const PING_INTERVAL_TICKS: u64 = 20;
+/// Weight of a new round trip sample in the smoothed estimate.
+const RTT_SMOOTHING: f32 = 0.2;
+
+pub const MAX_ROOM_PLAYERS: usize = 16;
+
+/// Bots get ids from here up, well clear of the ids the lobby hands out.
+const FIRST_BOT_ID: u64 = 1 << 40;
+const BOT_NAMES: &[&str] = &[
+    "Ace", "Blip", "Comet", "Dash", "Echo", "Flux", "Gizmo", "Halo", "Ion", "Jet", "Kilo", "Lux",
+    "Mote", "Nova", "Orbit", "Pixel",
+];
+
+pub type ClientTx = mpsc::UnboundedSender<ServerMsg>;
+
+/// A message produced by the tick loop, and how it reaches the client.
+enum Outgoing {
+    Stream(ClientTx, Box<ServerMsg>),
+    Datagram(SocketAddr, Vec<u8>),
+}
+
+/// The UDP side of a session that negotiated it.
+pub struct UdpPeer {
+    pub token: u64,
+    /// Source of the client's latest datagram; unknown until the first one
+    /// arrives, and updated if the client's address changes.
+    addr: Option<SocketAddr>,
+    recv_seq: u32,
+    send_seq: u32,
+    /// Newest input queued from a datagram, to skip the redundant copies.
+    last_input_seq: u32,
+}
+
+impl UdpPeer {
+    fn new(token: u64) -> Self {
+        Self {
+            token,
+            addr: None,
+            recv_seq: 0,
+            send_seq: 0,
+            last_input_seq: 0,
+        }
+    }
+}
+
+/// Everything the server tracks about one connection's traffic. It moves
+/// with the client from room to room; the per-room parts are reset on the way.
+pub struct ClientConn {
+    pub tx: ClientTx,
+    /// Features both sides agreed on in the handshake.
+    capabilities: Capabilities,
+    pub udp: Option<UdpPeer>,
+    /// Newest snapshot the client has confirmed it holds.
+    acked_tick: Option<u64>,
+    /// Tick of the room when the client joined it. Only later snapshots were
+    /// sent to it.
+    joined_tick: u64,
+    last_keyframe_tick: u64,
+    /// Nonce and send time of the ping awaiting a pong.
+    pending_ping: Option<(u64, Instant)>,
+    /// Smoothed round trip time in seconds, once measured.
+    rtt: Option<f32>,
+}
+
+impl ClientConn {
+    pub fn new(tx: ClientTx, capabilities: Capabilities, udp_token: Option<u64>) -> Self {
+        Self {
+            tx,
+            capabilities,
+            udp: udp_token.map(UdpPeer::new),
+            acked_tick: None,
+            joined_tick: 0,
+            last_keyframe_tick: 0,
+            pending_ping: None,
+            rtt: None,
+        }
+    }
+
+    /// Routes `msg` from room `room_id` over UDP once the client's address is
+    /// known, over the stream otherwise.
+    fn outgoing(&mut self, room_id: u64, msg: ServerMsg) -> Outgoing {
+        let Some(peer) = self.udp.as_mut() else {
+            return Outgoing::Stream(self.tx.clone(), Box::new(msg));
+        };
+        let Some(addr) = peer.addr else {
+            return Outgoing::Stream(self.tx.clone(), Box::new(msg));
+        };
+        let datagram = ServerDatagram {
+            seq: peer.send_seq + 1,
+            room_id,
+            msg,
+        };
+        match codec::encode_datagram(&datagram) {
+            Ok(bytes) => {
+                peer.send_seq += 1;
+                Outgoing::Datagram(addr, bytes)
+            }
+            // Too big for one datagram, most likely a keyframe
+            Err(_) => Outgoing::Stream(self.tx.clone(), Box::new(datagram.msg)),
+        }
+    }
+}
+
+pub struct Room {
+    id: u64,
+    name: String,
+    world: World,
+    /// Input applied to each player on the next tick. Held over from tick to
+    /// tick when a client's queue runs dry.
+    inputs: HashMap<u64, PlayerInput>,
+    /// Sequenced inputs not yet applied. Clients that predict their own ship
+    /// send one input per tick; the server consumes them one per tick too so
+    /// both sides integrate the same inputs.
+    input_queues: HashMap<u64, VecDeque<PlayerInput>>,
+    clients: HashMap<u64, ClientConn>,
+    /// Recently broadcast snapshots, oldest first.
+    history: VecDeque<Snapshot>,
+    last_ping_tick: u64,
+    bot_config: BotConfig,
+    /// Bots playing in the room, oldest first.
+    bots: BTreeMap<u64, Bot>,
+    next_bot_id: u64,
+    /// Where matches are recorded to, if they are.
+    record_dir: Option<PathBuf>,
+    /// Replay of the current match. Rooms nobody is in are not recorded.
+    recorder: Option<Recorder>,
+}
+
+impl Room {
+    pub fn new(
+        id: u64,
+        name: String,
+        seed: u64,
+        mode: GameMode,
+        rules: MatchRules,
+        bot_config: BotConfig,
+        record_dir: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            world: World::with_rules(seed, mode, rules),
+            inputs: HashMap::new(),
+            input_queues: HashMap::new(),
+            clients: HashMap::new(),
+            history: VecDeque::new(),
+            last_ping_tick: 0,
+            bot_config,
+            bots: BTreeMap::new(),
+            next_bot_id: FIRST_BOT_ID,
+            record_dir,
+            recorder: None,
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            players: self.clients.len() as u32,
+            max_players: MAX_ROOM_PLAYERS as u32,
+            mode: self.world.mode(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.clients.len() >= MAX_ROOM_PLAYERS
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Spawns a ship for `id` and starts sending it this room's states, from a
+    /// fresh keyframe.
+    pub fn add_client(&mut self, id: u64, name: String, mut conn: ClientConn) {
+        conn.acked_tick = None;
+        conn.joined_tick = self.world.tick();
+        conn.last_keyframe_tick = 0;
+        conn.pending_ping = None;
+        self.world.add_player(id, name);
+        if let Some(rtt) = conn.rtt {
+            self.world
+                .set_player_latency(id, rtt + INTERPOLATION_DELAY_SECS);
+        }
+        self.clients.insert(id, conn);
+    }
+
+    /// Takes `id` out of the room, returning its connection and the name its
+    /// ship had.
+    pub fn remove_client(&mut self, id: u64) -> Option<(ClientConn, Option<String>)> {
+        let conn = self.clients.remove(&id)?;
+        self.inputs.remove(&id);
+        self.input_queues.remove(&id);
+        let name = self.world.remove_player(id).map(|p| p.name);
+        Some((conn, name))
+    }
+
+    pub fn broadcast(&mut self, msg: ServerMsg) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(&msg);
+        }
+        for client in self.clients.values() {
+            let _ = client.tx.send(msg.clone());
+        }
+    }
+
+    pub fn queue_input(&mut self, id: u64, input: PlayerInput) {
+        if self.world.player(id).is_none() {
+            return;
+        }
+        if input.seq == 0 {
+            // Unsequenced clients just overwrite their current input
+            self.inputs.insert(id, input);
+            return;
+        }
+        let queue = self.input_queues.entry(id).or_default();
+        queue.push_back(input);
+        while queue.len() > MAX_QUEUED_INPUTS {
+            queue.pop_front();
+        }
+    }
+
+    /// Records that `id` holds the snapshot for `tick`. Acks still in flight
+    /// from the client's previous room are told apart by their tick: it
+    /// must be one this room sent since the client joined.
+    pub fn ack(&mut self, id: u64, tick: u64) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        if tick <= client.joined_tick || !self.history.iter().any(|s| s.tick == tick) {
+            return;
+        }
+        if client.acked_tick < Some(tick) {
+            client.acked_tick = Some(tick);
+        }
+    }
+
+    /// Finishes a round trip measurement and updates how far the player's
+    /// shots are lag compensated.
+    pub fn pong(&mut self, id: u64, nonce: u64) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        let Some((sent_nonce, sent_at)) = client.pending_ping else {
+            return;
+        };
+        if sent_nonce != nonce {
+            return;
+        }
+        client.pending_ping = None;
+        let sample = sent_at.elapsed().as_secs_f32();
+        let rtt = match client.rtt {
+            Some(rtt) => rtt + (sample - rtt) * RTT_SMOOTHING,
+            None => sample,
+        };
+        client.rtt = Some(rtt);
+        // The shooter sees other ships half a round trip late, aims, and the
+        // shot takes another half to arrive; on top of that they are drawn
+        // behind the newest snapshot
+        self.world
+            .set_player_latency(id, rtt + INTERPOLATION_DELAY_SECS);
+    }
+
+    /// Handles a datagram from `id`'s client, which was received from `from`.
+    pub fn receive_datagram(&mut self, id: u64, from: SocketAddr, datagram: ClientDatagram) {
+        let Some(peer) = self.clients.get_mut(&id).and_then(|c| c.udp.as_mut()) else {
+            return;
+        };
+        if peer.addr.is_some() && datagram.seq <= peer.recv_seq {
+            return;
+        }
+        peer.recv_seq = datagram.seq;
+        peer.addr = Some(from);
+
+        let last_input_seq = peer.last_input_seq;
+        let inputs: Vec<PlayerInput> = datagram
+            .inputs
+            .into_iter()
+            .filter(|input| input.seq > last_input_seq)
+            .collect();
+        if let Some(newest) = inputs.last() {
+            peer.last_input_seq = newest.seq;
+        }
+        if let Some(tick) = datagram.ack_tick {
+            self.ack(id, tick);
+        }
+        for input in inputs {
+            self.queue_input(id, input);
+        }
+    }
+
+    /// Advances the world one tick. Stream messages are queued right away,
+    /// while the room is locked, so they can never land after a client has
+    /// been told it moved to another room; datagrams are returned for the
+    /// caller to send.
+    fn tick(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.update_recorder();
+        self.balance_bots();
+        self.take_queued_inputs();
+        for (id, bot) in self.bots.iter_mut() {
+            self.inputs.insert(*id, bot.input(&self.world));
+        }
+        let phase = self.world.match_phase();
+        self.world.step(&self.inputs, TICK_DT);
+        if phase != MatchPhase::Ended && self.world.match_phase() == MatchPhase::Ended {
+            self.report_match_end();
+        }
+        let snapshot = self.world.snapshot();
+        self.record_state(phase, &snapshot);
+        let mut messages = self.snapshot_messages(&snapshot);
+        self.remember_snapshot(snapshot);
+        if self.world.tick() >= self.last_ping_tick + PING_INTERVAL_TICKS {
+            self.last_ping_tick = self.world.tick();
+            messages.extend(self.ping_messages());
+        }
+
+        let mut datagrams = Vec::new();
+        for outgoing in messages {
+            match outgoing {
+                Outgoing::Stream(tx, msg) => {
+                    let _ = tx.send(*msg);
+                }
+                Outgoing::Datagram(addr, bytes) => datagrams.push((addr, bytes)),
+            }
+        }
+        datagrams
+    }
+
+    /// Starts a replay once someone is in the room, and ends it when the
+    /// room empties.
+    fn update_recorder(&mut self) {
+        let Some(dir) = &self.record_dir else {
+            return;
+        };
+        if self.clients.is_empty() {
+            self.recorder = None;
+            return;
+        }
+        if self.recorder.is_some() {
+            return;
+        }
+        match Recorder::start(
+            dir,
+            self.id,
+            &self.name,
+            self.world.seed(),
+            self.world.mode(),
+        ) {
+            Ok(recorder) => {
+                log!(
+                    "room {}: recording to {}",
+                    self.name,
+                    recorder.path().display()
+                );
+                self.recorder = Some(recorder);
+            }
+            Err(err) => {
+                log!("room {}: recording stopped: {err}", self.name);
+                self.record_dir = None;
+            }
+        }
+    }
+
+    /// Adds `snapshot` to the current replay, first moving on to a new file
+    /// if a new match just started (the world was in `phase` before this
+    /// tick).
+    fn record_state(&mut self, phase: MatchPhase, snapshot: &Snapshot) {
+        if phase == MatchPhase::Intermission && self.world.match_phase() == MatchPhase::Warmup {
+            self.recorder = None;
+            self.update_recorder();
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record_state(snapshot);
+        }
+    }
+
+    fn report_match_end(&mut self) {
+        let standings = self.world.standings();
+        if let Some(winner) = standings.first() {
+            log!(
+                "room {}: match won by {} with {} points",
+                self.name,
+                winner.name,
+                winner.score
+            );
+        }
+        self.broadcast(ServerMsg::MatchEnded {
+            standings,
+            teams: self.world.team_scores(),
+            coop: self.world.coop_info(),
+            wave: self.world.current_wave(),
+        });
+    }
+
+    /// Picks the message each client gets for `snapshot`: a delta against the
+    /// last snapshot it acknowledged if that is still in the history, a full
+    /// keyframe otherwise. Deltas are computed once per distinct baseline.
+    /// Clients with a bound UDP address get it as a datagram when it fits.
+    fn snapshot_messages(&mut self, snapshot: &Snapshot) -> Vec<Outgoing> {
+        let mut deltas: HashMap<u64, ServerMsg> = HashMap::new();
+        let mut messages = Vec::with_capacity(self.clients.len());
+        for client in self.clients.values_mut() {
+            let keyframe_due =
+                snapshot.tick.saturating_sub(client.last_keyframe_tick) >= KEYFRAME_INTERVAL_TICKS;
+            let base = client
+                .acked_tick
+                .filter(|_| !keyframe_due)
+                .filter(|_| client.capabilities.contains(Capabilities::DELTA_SNAPSHOTS))
+                .and_then(|tick| self.history.iter().find(|s| s.tick == tick));
+            let msg = match base {
+                Some(base) => deltas
+                    .entry(base.tick)
+                    .or_insert_with(|| ServerMsg::Delta(Box::new(base.diff(snapshot))))
+                    .clone(),
+                None => {
+                    client.last_keyframe_tick = snapshot.tick;
+                    ServerMsg::State(snapshot.clone())
+                }
+            };
+            messages.push(client.outgoing(self.id, msg));
+        }
+        messages
+    }
+
+    /// Starts a round trip measurement for every client. A ping that was
+    /// never answered is simply replaced.
+    fn ping_messages(&mut self) -> Vec<Outgoing> {
+        let nonce = self.world.tick();
+        let now = Instant::now();
+        self.clients
+            .values_mut()
+            .map(|client| {
+                client.pending_ping = Some((nonce, now));
+                Outgoing::Stream(client.tx.clone(), Box::new(ServerMsg::Ping { nonce }))
+            })
+            .collect()
+    }
+
+    fn remember_snapshot(&mut self, snapshot: Snapshot) {
+        self.history.push_back(snapshot);
+        while self.history.len() > SNAPSHOT_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    /// Adds bots until the room has its minimum number of players, and takes
+    /// the newest ones out again as people join. Empty rooms have no bots.
+    fn balance_bots(&mut self) {
+        let wanted = if self.clients.is_empty() {
+            0
+        } else {
+            self.bot_config
+                .min_players
+                .min(MAX_ROOM_PLAYERS)
+                .saturating_sub(self.clients.len())
+        };
+        while self.bots.len() < wanted {
+            let id = self.next_bot_id;
+            self.next_bot_id += 1;
+            let name = format!(
+                "{} (bot)",
+                BOT_NAMES[(id - FIRST_BOT_ID) as usize % BOT_NAMES.len()]
+            );
+            let seed = self.world.seed() ^ id;
+            self.bots
+                .insert(id, Bot::new(id, self.bot_config.difficulty, seed));
+            self.world.add_player(id, name.clone());
+            self.broadcast(ServerMsg::System {
+                text: format!("{name} joined the room"),
+            });
+        }
+        while self.bots.len() > wanted {
+            let Some((id, _)) = self.bots.pop_last() else {
+                break;
+            };
+            self.inputs.remove(&id);
+            if let Some(player) = self.world.remove_player(id) {
+                self.broadcast(ServerMsg::System {
+                    text: format!("{} left the room", player.name),
+                });
+            }
+        }
+    }
+
+    fn take_queued_inputs(&mut self) {
+        for (id, queue) in self.input_queues.iter_mut() {
+            if let Some(input) = queue.pop_front() {
+                self.inputs.insert(*id, input);
+            }
+        }
+    }
+}
+
+/// Runs `room`'s tick loop until the task is aborted. Without a socket no
+/// client can have negotiated UDP, so there are no datagrams to send.
+pub fn spawn_tick_loop(room: Arc<Mutex<Room>>, udp: Option<Arc<UdpSocket>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_millis(1000 / TICK_HZ as u64));
+        loop {
+            ticker.tick().await;
+            let datagrams = room.lock().await.tick();
+            let Some(udp) = &udp else {
+                continue;
+            };
+            for (addr, bytes) in datagrams {
+                let _ = udp.send_to(&bytes, addr).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::sim::{secs_to_ticks, WARMUP_SECS};
+
+    use super::*;
+
+    fn test_room(id: u64) -> Room {
+        Room::new(
+            id,
+            format!("room {id}"),
+            id,
+            GameMode::FreeForAll,
+            MatchRules::default(),
+            BotConfig::default(),
+            None,
+        )
+    }
+
+    /// Ticks `room` and returns the state or delta the client got for it.
+    fn tick_state(room: &mut Room, rx: &mut mpsc::UnboundedReceiver<ServerMsg>) -> ServerMsg {
+        room.tick();
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .find(|msg| matches!(msg, ServerMsg::State(_) | ServerMsg::Delta(_)))
+            .expect("no state was sent")
+    }
+
+    #[test]
+    fn acks_from_the_previous_room_are_ignored() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut old = test_room(1);
+        old.add_client(
+            7,
+            "ace".into(),
+            ClientConn::new(tx, Capabilities::DELTA_SNAPSHOTS, None),
+        );
+        for _ in 0..50 {
+            old.tick();
+        }
+        let mut new = test_room(2);
+        for _ in 0..5 {
+            new.tick();
+        }
+        let (conn, _) = old.remove_client(7).unwrap();
+        new.add_client(7, "ace".into(), conn);
+        while rx.try_recv().is_ok() {}
+
+        // Acks of the old room's states, still arriving after the switch. One
+        // is ahead of the new room, the other matches a state sent before the
+        // client joined
+        new.ack(7, 50);
+        new.ack(7, 3);
+        let ServerMsg::State(first) = tick_state(&mut new, &mut rx) else {
+            panic!("the first state in a room must be a keyframe");
+        };
+
+        new.ack(7, 49);
+        new.ack(7, first.tick);
+        match tick_state(&mut new, &mut rx) {
+            ServerMsg::Delta(delta) => assert_eq!(delta.base_tick, first.tick),
+            msg => panic!("expected a delta, got {msg:?}"),
+        }
+    }
+
+    #[test]
+    fn a_timed_match_reports_its_standings() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rules = MatchRules {
+            time_limit_secs: Some(1),
+            score_limit: None,
+        };
+        let mut room = Room::new(
+            1,
+            "timed".into(),
+            1,
+            GameMode::FreeForAll,
+            rules,
+            BotConfig::default(),
+            None,
+        );
+        room.add_client(
+            7,
+            "ace".into(),
+            ClientConn::new(tx, Capabilities::default(), None),
+        );
+        for _ in 0..secs_to_ticks(WARMUP_SECS + 1.0) {
+            room.tick();
+        }
+
+        let standings = std::iter::from_fn(|| rx.try_recv().ok())
+            .find_map(|msg| match msg {
+                ServerMsg::MatchEnded { standings, .. } => Some(standings),
+                _ => None,
+            })
+            .expect("the match did not end at its time limit");
+        assert_eq!(room.world.match_phase(), MatchPhase::Ended);
+        assert!(standings.iter().any(|s| s.id == 7 && s.name == "ace"));
+    }
+}
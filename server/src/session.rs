@@ -0,0 +1,154 @@
+//! What the server does with a client's messages once it has joined, no
+//! matter how they arrive: over the network, or over channels from a client
+//! in the same process (see [`crate::local`]).
+
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use shared::{Capabilities, ClientMsg, ServerMsg};
+
+use crate::lobby::{Lobby, SharedRoom};
+use crate::room::ClientTx;
+
+pub struct ClientSession {
+    id: u64,
+    name: String,
+    lobby: Arc<Mutex<Lobby>>,
+    tx: ClientTx,
+    /// The room this client plays in. Only the session moves it between
+    /// rooms, so this copy never goes stale.
+    room: Option<SharedRoom>,
+}
+
+impl ClientSession {
+    /// Registers a client that finished the handshake. Returns the session and
+    /// the client's UDP token, if it negotiated UDP.
+    pub async fn connect(
+        lobby: Arc<Mutex<Lobby>>,
+        name: String,
+        tx: ClientTx,
+        capabilities: Capabilities,
+    ) -> (Self, Option<u64>) {
+        let (id, udp_token) = lobby
+            .lock()
+            .await
+            .connect(name.clone(), tx.clone(), capabilities);
+        let session = Self {
+            id,
+            name,
+            lobby,
+            tx,
+            room: None,
+        };
+        (session, udp_token)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Greets the client after its `Welcome` and shows it the lobby.
+    pub async fn start(&mut self) {
+        let _ = self.tx.send(ServerMsg::System {
+            text: "Welcome to rusted-asteroids".to_string(),
+        });
+        self.send_room_list().await;
+    }
+
+    pub async fn handle(&mut self, msg: ClientMsg) {
+        let id = self.id;
+        match msg {
+            ClientMsg::Hello { .. } | ClientMsg::Join { .. } => {}
+            ClientMsg::Input(input) => {
+                if let Some(room) = &self.room {
+                    room.lock().await.queue_input(id, input);
+                }
+            }
+            ClientMsg::Chat { text } => {
+                let msg = ServerMsg::Chat {
+                    from: self.name.clone(),
+                    text,
+                };
+                match &self.room {
+                    Some(room) => room.lock().await.broadcast(msg),
+                    None => self.lobby.lock().await.broadcast_lobby(msg),
+                }
+            }
+            ClientMsg::Ping { nonce } => {
+                let _ = self.tx.send(ServerMsg::Pong { nonce });
+            }
+            ClientMsg::Pong { nonce } => {
+                if let Some(room) = &self.room {
+                    room.lock().await.pong(id, nonce);
+                }
+            }
+            ClientMsg::Ack { tick } => {
+                if let Some(room) = &self.room {
+                    room.lock().await.ack(id, tick);
+                }
+            }
+            ClientMsg::ListRooms => self.send_room_list().await,
+            ClientMsg::CreateRoom { name, mode } => {
+                let joined = self.lobby.lock().await.create_room(id, &name, mode).await;
+                self.enter_room(joined);
+            }
+            ClientMsg::JoinRoom { room_id } => {
+                let joined = self.lobby.lock().await.join_room(id, room_id).await;
+                self.enter_room(joined);
+            }
+            ClientMsg::CreatePrivateRoom {
+                name,
+                password,
+                mode,
+            } => {
+                let joined = self
+                    .lobby
+                    .lock()
+                    .await
+                    .create_private_room(id, &name, password.as_deref(), mode)
+                    .await;
+                self.enter_room(joined);
+            }
+            ClientMsg::JoinPrivateRoom { name, password } => {
+                let joined = self
+                    .lobby
+                    .lock()
+                    .await
+                    .join_private_room(id, &name, &password)
+                    .await;
+                self.enter_room(joined);
+            }
+            ClientMsg::JoinByInvite { code } => {
+                let joined = self.lobby.lock().await.join_by_invite(id, &code).await;
+                self.enter_room(joined);
+            }
+            ClientMsg::LeaveRoom => {
+                if self.lobby.lock().await.leave_room(id).await {
+                    self.room = None;
+                    let _ = self.tx.send(ServerMsg::RoomLeft);
+                    self.send_room_list().await;
+                }
+            }
+        }
+    }
+
+    /// Takes the client out of its room and the lobby.
+    pub async fn disconnect(self) {
+        self.lobby.lock().await.disconnect(self.id).await;
+    }
+
+    fn enter_room(&mut self, joined: Result<SharedRoom, String>) {
+        match joined {
+            Ok(joined) => self.room = Some(joined),
+            Err(text) => {
+                let _ = self.tx.send(ServerMsg::System { text });
+            }
+        }
+    }
+
+    async fn send_room_list(&self) {
+        let rooms = self.lobby.lock().await.room_list().await;
+        let _ = self.tx.send(ServerMsg::RoomList { rooms });
+    }
+}
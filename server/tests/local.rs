@@ -0,0 +1,36 @@
+//! Plays against a server running in the test's own process.
+
+use std::time::Duration;
+
+use headless::{Client, Connection, Event};
+use server::bot::BotConfig;
+use server::local::LocalServer;
+use shared::ServerMsg;
+
+#[tokio::test]
+async fn local_client_joins_a_room_and_receives_states() {
+    let server = LocalServer::new(1, server::default_rules(), BotConfig::default());
+    let (tx, rx) = server.connect("local".into()).await;
+    let mut client = Client::from_connection(Connection {
+        tx,
+        rx,
+        traffic: Default::default(),
+    });
+
+    let played = async {
+        loop {
+            match client.next_event().await.expect("server went away") {
+                Event::Message(ServerMsg::RoomList { rooms }) if client.room().is_none() => {
+                    client.join_room(rooms[0].id);
+                }
+                Event::State(snapshot) => break snapshot,
+                Event::Message(_) => {}
+            }
+        }
+    };
+    let snapshot = tokio::time::timeout(Duration::from_secs(10), played)
+        .await
+        .expect("no state arrived in time");
+    let id = client.id().unwrap();
+    assert!(snapshot.players.iter().any(|p| p.id == id));
+}
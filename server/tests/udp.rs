@@ -0,0 +1,94 @@
+//! Runs the server binary on loopback and plays against it over UDP.
+
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use headless::{Client, ConnectOptions, Event};
+use shared::codec::Encoding;
+use shared::{PlayerInput, ServerMsg};
+
+/// Kills the server when the test ends, however it ends.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Starts the server on a free loopback port and waits until it listens.
+fn start_server() -> (ServerProcess, String) {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let addr = format!("127.0.0.1:{port}");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg(format!("--addr={addr}"))
+        .arg("--seed=1")
+        .env_remove("ASTEROIDS_ADDR")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let server = ServerProcess(child);
+
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.starts_with("server listening"), "server said {line:?}");
+    // Keep the pipe drained so the server never blocks on its console
+    std::thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+    (server, addr)
+}
+
+#[tokio::test]
+async fn inputs_and_states_travel_over_udp() {
+    let (_server, addr) = start_server();
+    let options = ConnectOptions {
+        addr,
+        name: "udp".into(),
+        encoding: Encoding::Binary,
+        udp: true,
+    };
+    let mut client = Client::connect(&options).await.unwrap();
+
+    let played = async {
+        let mut seq = 0;
+        loop {
+            match client.next_event().await.expect("server went away") {
+                Event::Message(ServerMsg::Welcome { udp_token, .. }) => {
+                    assert!(udp_token.is_some(), "server did not agree to UDP");
+                }
+                Event::Message(ServerMsg::RoomList { rooms }) if client.room().is_none() => {
+                    client.join_room(rooms[0].id);
+                }
+                Event::State(snapshot) => {
+                    let id = client.id().unwrap();
+                    let acked = snapshot
+                        .players
+                        .iter()
+                        .find(|p| p.id == id)
+                        .map_or(0, |p| p.last_input_seq);
+                    if acked >= 10 && client.traffic().datagrams_received() > 0 {
+                        break;
+                    }
+                    seq += 1;
+                    client.send_input(PlayerInput {
+                        thrust: true,
+                        target_angle: None,
+                        fire: false,
+                        seq,
+                    });
+                }
+                Event::Message(_) => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), played)
+        .await
+        .expect("inputs were not acknowledged over UDP in time");
+}
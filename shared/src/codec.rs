@@ -0,0 +1,424 @@
+//! Wire encodings for [`ClientMsg`](crate::ClientMsg) and
+//! [`ServerMsg`](crate::ServerMsg).
+//!
+//! Every connection starts out with newline-delimited JSON. The client lists
+//! [`Capabilities::BINARY_ENCODING`](crate::Capabilities::BINARY_ENCODING) in
+//! its `Hello` if it can speak binary, and the server confirms the choice in
+//! `Welcome`; everything after that uses the agreed encoding in both
+//! directions. JSON stays available as a readable fallback for debugging.
+//!
+//! Binary frames are a big-endian `u32` payload length followed by a bincode
+//! payload (varint integers). In binary, [`Vec2`](crate::Vec2)s are quantized
+//! to 1/64 of a unit and headings to 1/65536 of a turn; see [`quantize`].
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::udp::MAX_DATAGRAM_LEN;
+use crate::Capabilities;
+
+/// Largest frame either side will accept.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// One JSON document per line.
+    #[default]
+    Json,
+    /// Length-prefixed bincode frames with quantized positions and angles.
+    Binary,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid binary frame: {0}")]
+    Binary(#[from] bincode::Error),
+    #[error("frame of {0} bytes exceeds the {MAX_FRAME_LEN} byte limit")]
+    FrameTooLarge(usize),
+    #[error("datagram of {0} bytes exceeds the {MAX_DATAGRAM_LEN} byte limit")]
+    DatagramTooLarge(usize),
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_FRAME_LEN as u64)
+}
+
+impl Encoding {
+    /// The encoding to use once both sides have agreed on `capabilities`.
+    pub fn negotiated(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::BINARY_ENCODING) {
+            Encoding::Binary
+        } else {
+            Encoding::Json
+        }
+    }
+}
+
+/// Serializes `msg` into a complete frame, ready to be written to the stream.
+pub fn encode<T: Serialize>(encoding: Encoding, msg: &T) -> Result<Vec<u8>, CodecError> {
+    match encoding {
+        Encoding::Json => {
+            let mut line = serde_json::to_vec(msg)?;
+            line.push(b'\n');
+            Ok(line)
+        }
+        Encoding::Binary => {
+            let payload = bincode_options().serialize(msg)?;
+            if payload.len() > MAX_FRAME_LEN {
+                return Err(CodecError::FrameTooLarge(payload.len()));
+            }
+            let mut frame = Vec::with_capacity(4 + payload.len());
+            frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&payload);
+            Ok(frame)
+        }
+    }
+}
+
+/// Deserializes one frame payload: a JSON line without its newline, or a
+/// binary frame without its length prefix.
+pub fn decode<T: DeserializeOwned>(encoding: Encoding, payload: &[u8]) -> Result<T, CodecError> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::from_slice(payload)?),
+        Encoding::Binary => {
+            if payload.len() > MAX_FRAME_LEN {
+                return Err(CodecError::FrameTooLarge(payload.len()));
+            }
+            Ok(bincode_options().deserialize(payload)?)
+        }
+    }
+}
+
+/// Serializes `msg` into a single UDP datagram in the binary encoding.
+pub fn encode_datagram<T: Serialize>(msg: &T) -> Result<Vec<u8>, CodecError> {
+    let payload = bincode_options().serialize(msg)?;
+    if payload.len() > MAX_DATAGRAM_LEN {
+        return Err(CodecError::DatagramTooLarge(payload.len()));
+    }
+    Ok(payload)
+}
+
+pub fn decode_datagram<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CodecError> {
+    Ok(bincode_options().deserialize(payload)?)
+}
+
+/// Fixed-point conversions used by the binary encoding.
+pub mod quantize {
+    use std::f32::consts::TAU;
+
+    const POS_SCALE: f32 = 64.0;
+
+    /// World coordinate or velocity component to 1/64 units; covers ±512.
+    pub fn coord(v: f32) -> i16 {
+        (v * POS_SCALE)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+
+    pub fn coord_from(q: i16) -> f32 {
+        q as f32 / POS_SCALE
+    }
+
+    /// Heading in radians to a fraction of a full turn.
+    pub fn angle(a: f32) -> u16 {
+        let turns = (a / TAU).rem_euclid(1.0);
+        ((turns * 65536.0).round() as u32 % 65536) as u16
+    }
+
+    pub fn angle_from(q: u16) -> f32 {
+        q as f32 / 65536.0 * TAU
+    }
+}
+
+/// `#[serde(with)]` helper that quantizes a heading in binary encodings.
+pub mod angle {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::quantize;
+
+    pub fn serialize<S: Serializer>(a: &f32, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            a.serialize(serializer)
+        } else {
+            quantize::angle(*a).serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+        if deserializer.is_human_readable() {
+            f32::deserialize(deserializer)
+        } else {
+            u16::deserialize(deserializer).map(quantize::angle_from)
+        }
+    }
+}
+
+/// Like [`angle`], for optional headings.
+pub mod opt_angle {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::quantize;
+
+    pub fn serialize<S: Serializer>(a: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            a.serialize(serializer)
+        } else {
+            a.map(quantize::angle).serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f32>, D::Error> {
+        if deserializer.is_human_readable() {
+            Option::<f32>::deserialize(deserializer)
+        } else {
+            Option::<u16>::deserialize(deserializer).map(|a| a.map(quantize::angle_from))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::f32::consts::TAU;
+
+    use super::*;
+    use crate::sim::{World, TICK_DT};
+    use crate::{
+        ClientMsg, CoopInfo, GameMode, PlayerInput, RoomInfo, ServerMsg, Standing, TeamScore,
+    };
+
+    fn room() -> RoomInfo {
+        RoomInfo {
+            id: 7,
+            name: "arena".into(),
+            players: 3,
+            max_players: 8,
+            mode: GameMode::TeamDeathmatch {
+                friendly_fire: true,
+            },
+        }
+    }
+
+    fn client_msgs() -> Vec<ClientMsg> {
+        vec![
+            ClientMsg::Hello {
+                protocol_version: crate::PROTOCOL_VERSION,
+                client_version: "1.2.3".into(),
+                capabilities: Capabilities::SUPPORTED,
+            },
+            ClientMsg::Join { name: "ace".into() },
+            ClientMsg::Input(PlayerInput {
+                thrust: true,
+                target_angle: Some(1.25),
+                fire: true,
+                seq: 42,
+            }),
+            ClientMsg::Chat {
+                text: "hello".into(),
+            },
+            ClientMsg::Ping { nonce: u64::MAX },
+            ClientMsg::Pong { nonce: 3 },
+            ClientMsg::Ack { tick: 1000 },
+            ClientMsg::ListRooms,
+            ClientMsg::CreateRoom {
+                name: "arena".into(),
+                mode: GameMode::Coop,
+            },
+            ClientMsg::JoinRoom { room_id: 7 },
+            ClientMsg::LeaveRoom,
+            ClientMsg::CreatePrivateRoom {
+                name: "den".into(),
+                password: Some("secret".into()),
+                mode: GameMode::BattleRoyale,
+            },
+            ClientMsg::JoinPrivateRoom {
+                name: "den".into(),
+                password: "secret".into(),
+            },
+            ClientMsg::JoinByInvite {
+                code: "ABCD1234".into(),
+            },
+        ]
+    }
+
+    /// One of every server message, with states taken from a running world.
+    fn server_msgs() -> Vec<ServerMsg> {
+        let mut world = World::with_mode(5, GameMode::Coop);
+        world.add_player(1, "ace".into());
+        world.add_player(2, "bee".into());
+        let inputs: HashMap<u64, PlayerInput> = [1, 2]
+            .into_iter()
+            .map(|id| {
+                let input = PlayerInput {
+                    thrust: true,
+                    target_angle: Some(id as f32),
+                    fire: true,
+                    seq: 1,
+                };
+                (id, input)
+            })
+            .collect();
+        let base = world.snapshot();
+        for _ in 0..30 {
+            world.step(&inputs, TICK_DT);
+        }
+        let newer = world.snapshot();
+
+        vec![
+            ServerMsg::Welcome {
+                id: 1,
+                tick_hz: 30,
+                protocol_version: crate::PROTOCOL_VERSION,
+                server_version: "1.2.3".into(),
+                capabilities: Capabilities::SUPPORTED,
+                encoding: Encoding::Binary,
+                udp_token: Some(0xdead_beef),
+            },
+            ServerMsg::Rejected {
+                reason: "full".into(),
+            },
+            ServerMsg::State(newer.clone()),
+            ServerMsg::Delta(Box::new(base.diff(&newer))),
+            ServerMsg::Chat {
+                from: "ace".into(),
+                text: "gg".into(),
+            },
+            ServerMsg::System {
+                text: "bee joined".into(),
+            },
+            ServerMsg::Pong { nonce: 9 },
+            ServerMsg::Ping { nonce: 10 },
+            ServerMsg::RoomList {
+                rooms: vec![room()],
+            },
+            ServerMsg::RoomJoined { room: room() },
+            ServerMsg::RoomLeft,
+            ServerMsg::MatchEnded {
+                standings: vec![Standing {
+                    id: 1,
+                    name: "ace".into(),
+                    score: 120,
+                    team: Some(0),
+                }],
+                teams: vec![TeamScore {
+                    team: 0,
+                    score: 120,
+                }],
+                coop: Some(CoopInfo {
+                    lives: 2,
+                    score: 300,
+                }),
+                wave: 4,
+            },
+        ]
+    }
+
+    /// Decodes a whole frame as written by [`encode`].
+    fn roundtrip<T: Serialize + DeserializeOwned>(encoding: Encoding, msg: &T) -> T {
+        let frame = encode(encoding, msg).unwrap();
+        let payload = match encoding {
+            Encoding::Json => {
+                assert_eq!(frame.last(), Some(&b'\n'));
+                &frame[..frame.len() - 1]
+            }
+            Encoding::Binary => {
+                let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+                assert_eq!(len, frame.len() - 4);
+                &frame[4..]
+            }
+        };
+        decode(encoding, payload).unwrap()
+    }
+
+    #[test]
+    fn coordinates_are_kept_to_half_a_step() {
+        let mut v = -511.0;
+        while v < 511.0 {
+            let error = (quantize::coord_from(quantize::coord(v)) - v).abs();
+            assert!(error <= 1.0 / 128.0, "{v} came back {error} off");
+            v += 0.37;
+        }
+    }
+
+    #[test]
+    fn coordinates_out_of_range_are_clamped() {
+        assert_eq!(quantize::coord(1000.0), i16::MAX);
+        assert_eq!(quantize::coord(-1000.0), i16::MIN);
+        assert_eq!(quantize::coord_from(i16::MIN), -512.0);
+    }
+
+    #[test]
+    fn angles_are_kept_to_half_a_step_of_the_same_heading() {
+        let half_step = TAU / 65536.0 / 2.0;
+        let mut a = -20.0_f32;
+        while a < 20.0 {
+            let decoded = quantize::angle_from(quantize::angle(a));
+            assert!((0.0..TAU).contains(&decoded), "{a} came back as {decoded}");
+            let diff = (decoded - a).rem_euclid(TAU);
+            let error = diff.min(TAU - diff);
+            // Allow for the rounding of `a` itself far from zero
+            assert!(error <= half_step + 1e-5, "{a} came back {error} off");
+            a += 0.013;
+        }
+    }
+
+    #[test]
+    fn every_client_message_survives_both_encodings() {
+        for msg in client_msgs() {
+            assert_eq!(roundtrip(Encoding::Json, &msg), msg);
+            assert_eq!(roundtrip(Encoding::Binary, &msg), msg);
+        }
+    }
+
+    #[test]
+    fn every_server_message_survives_both_encodings() {
+        for msg in server_msgs() {
+            assert_eq!(roundtrip(Encoding::Json, &msg), msg);
+            // Positions and headings come back quantized, after which the
+            // binary encoding is lossless
+            let quantized = roundtrip(Encoding::Binary, &msg);
+            assert_eq!(roundtrip(Encoding::Binary, &quantized), quantized);
+        }
+    }
+
+    #[test]
+    fn datagrams_roundtrip() {
+        for msg in client_msgs() {
+            let decoded: ClientMsg = decode_datagram(&encode_datagram(&msg).unwrap()).unwrap();
+            assert_eq!(decoded, msg);
+        }
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_rejected() {
+        let msg = ServerMsg::Chat {
+            from: "ace".into(),
+            text: "x".repeat(MAX_FRAME_LEN),
+        };
+        assert!(encode(Encoding::Binary, &msg).is_err());
+
+        // A peer without the limit could still send one
+        let payload = bincode::DefaultOptions::new().serialize(&msg).unwrap();
+        assert!(payload.len() > MAX_FRAME_LEN);
+        assert!(matches!(
+            decode::<ServerMsg>(Encoding::Binary, &payload),
+            Err(CodecError::FrameTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn datagrams_over_the_limit_are_rejected() {
+        let msg = ServerMsg::System {
+            text: "x".repeat(MAX_DATAGRAM_LEN),
+        };
+        assert!(matches!(
+            encode_datagram(&msg),
+            Err(CodecError::DatagramTooLarge(_))
+        ));
+    }
+}
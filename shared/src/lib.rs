@@ -1,4 +1,13 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub mod codec;
+pub mod replay;
+pub mod sim;
+pub mod snapshot;
+pub mod udp;
+
+use codec::Encoding;
+use snapshot::{Snapshot, SnapshotDelta};
 
 pub const WORLD_WIDTH: f32 = 240.0;
 pub const WORLD_HEIGHT: f32 = 80.0;
@@ -11,14 +20,29 @@ pub enum PowerUpKind {
     SpeedBoost,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What an asteroid does besides drifting and splitting. Special kinds get
+/// more common as the waves go on.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum AsteroidKind {
+    #[default]
+    Normal,
+    /// Blows up when destroyed, taking nearby asteroids with it and
+    /// damaging ships in the blast.
+    Explosive,
+    /// Drifts toward the nearest ship.
+    Magnetic,
+    /// Shatters into a ring of small fragments instead of splitting in two.
+    Cluster,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct PowerUpState {
     pub id: u64,
     pub pos: Vec2,
     pub kind: PowerUpKind,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct PlayerEffects {
     pub shield_remaining: Option<f32>,
     pub rapid_fire_remaining: Option<f32>,
@@ -27,14 +51,34 @@ pub struct PlayerEffects {
     pub invincible_remaining: Option<f32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct WaveInfo {
     pub wave_number: u32,
     pub asteroids_remaining: u32,
     pub countdown: Option<f32>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+/// An enemy saucer hunting the players. Its bullets are owned by its id.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UfoState {
+    pub id: u64,
+    pub pos: Vec2,
+    pub vel: Vec2,
+    pub health: f32,
+}
+
+/// The mothership that turns up every few waves. Its bullets are owned by its
+/// id.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BossState {
+    pub id: u64,
+    pub pos: Vec2,
+    pub vel: Vec2,
+    pub health: f32,
+    pub max_health: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Vec2 {
     pub x: f32,
     pub y: f32,
@@ -45,6 +89,7 @@ impl Vec2 {
         Self { x, y }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn add(self, other: Vec2) -> Self {
         Self::new(self.x + other.x, self.y + other.y)
     }
@@ -54,20 +99,63 @@ impl Vec2 {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+// Serialized as `{"x":..,"y":..}` in JSON and as two quantized `i16`s in the
+// binary encoding.
+impl Serialize for Vec2 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            #[derive(Serialize)]
+            struct Vec2 {
+                x: f32,
+                y: f32,
+            }
+            Vec2 { x: self.x, y: self.y }.serialize(serializer)
+        } else {
+            (codec::quantize::coord(self.x), codec::quantize::coord(self.y)).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Vec2 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            #[derive(Deserialize)]
+            struct Vec2 {
+                x: f32,
+                y: f32,
+            }
+            let v = Vec2::deserialize(deserializer)?;
+            Ok(Self::new(v.x, v.y))
+        } else {
+            let (x, y) = <(i16, i16)>::deserialize(deserializer)?;
+            Ok(Self::new(
+                codec::quantize::coord_from(x),
+                codec::quantize::coord_from(y),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
 pub struct PlayerInput {
     pub thrust: bool,
     #[serde(default)]
     pub target_angle: Option<f32>,
     pub fire: bool,
+    /// Increasing sequence number assigned by the client; echoed back in
+    /// [`PlayerState::last_input_seq`] once the server has applied it.
+    /// Zero means the client does not sequence its inputs.
+    #[serde(default)]
+    pub seq: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct PlayerState {
     pub id: u64,
     pub name: String,
     pub pos: Vec2,
     pub vel: Vec2,
+    #[serde(with = "codec::angle")]
     pub angle: f32,
     pub alive: bool,
     pub score: u32,
@@ -79,47 +167,317 @@ pub struct PlayerState {
     pub respawn_timer: Option<f32>,
     #[serde(default)]
     pub effects: PlayerEffects,
+    #[serde(default)]
+    pub last_input_seq: u32,
+    /// Team the player plays for in team modes.
+    #[serde(default)]
+    pub team: Option<u8>,
+    /// Out of [`sim::MAX_HEALTH`]; the ship is destroyed when it runs out.
+    #[serde(default)]
+    pub health: f32,
+    /// Damage the ship's shield can still absorb.
+    #[serde(default)]
+    pub shield: f32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct AsteroidState {
     pub id: u64,
     pub pos: Vec2,
     pub vel: Vec2,
     pub size: u8,
+    /// Hits taken so far. The asteroid breaks once this reaches
+    /// [`sim::asteroid_hit_points`] for its size.
+    #[serde(default)]
+    pub damage: u8,
+    #[serde(default)]
+    pub kind: AsteroidKind,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct BulletState {
     pub id: u64,
     pub owner_id: u64,
+    /// Fired by the boss or a UFO rather than a player. Their ids can match
+    /// a player's, so `owner_id` alone does not tell.
+    #[serde(default)]
+    pub enemy: bool,
     pub pos: Vec2,
     pub vel: Vec2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Version of the message protocol spoken by this build. Bump it whenever a
+/// change would confuse peers built before it.
+pub const PROTOCOL_VERSION: u32 = 13;
+/// Oldest protocol either side will talk to. Raise it along with
+/// [`PROTOCOL_VERSION`] when older peers can no longer read the messages.
+/// Clients that predate `Hello` count as version 0.
+pub const MIN_PROTOCOL_VERSION: u32 = 13;
+
+/// Optional protocol features, as bit flags. Unknown bits from newer peers are
+/// ignored.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Length-prefixed binary frames (see [`codec`]).
+    pub const BINARY_ENCODING: Self = Self(1 << 0);
+    /// Delta snapshots against acknowledged ticks (see [`snapshot`]).
+    pub const DELTA_SNAPSHOTS: Self = Self(1 << 1);
+    /// Snapshots, inputs and acks over UDP (see [`udp`]).
+    pub const UDP_TRANSPORT: Self = Self(1 << 2);
+    /// Everything this build understands.
+    pub const SUPPORTED: Self =
+        Self(Self::BINARY_ENCODING.0 | Self::DELTA_SNAPSHOTS.0 | Self::UDP_TRANSPORT.0);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl std::ops::Not for Capabilities {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
+
+/// Rules a room is played under, chosen when the room is created.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum GameMode {
+    /// Everyone for themselves.
+    #[default]
+    FreeForAll,
+    /// Two teams. Bullets pass through teammates unless `friendly_fire` is
+    /// on; teamkills never score.
+    TeamDeathmatch { friendly_fire: bool },
+    /// Everyone against the asteroids with a shared pool of lives; bullets
+    /// pass through other players.
+    Coop,
+    /// One life per match inside a shrinking safe zone; the last ship
+    /// standing wins.
+    BattleRoyale,
+}
+
+impl GameMode {
+    pub fn has_teams(self) -> bool {
+        matches!(self, GameMode::TeamDeathmatch { .. })
+    }
+}
+
+impl std::fmt::Display for GameMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameMode::FreeForAll => f.write_str("Free for all"),
+            GameMode::TeamDeathmatch {
+                friendly_fire: false,
+            } => f.write_str("Team deathmatch"),
+            GameMode::TeamDeathmatch {
+                friendly_fire: true,
+            } => f.write_str("Team deathmatch, friendly fire"),
+            GameMode::Coop => f.write_str("Co-op"),
+            GameMode::BattleRoyale => f.write_str("Battle royale"),
+        }
+    }
+}
+
+/// State of a co-op run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CoopInfo {
+    /// Respawns left for the whole room.
+    pub lives: u32,
+    /// Points every player has earned this run, not cut by deaths.
+    pub score: u32,
+}
+
+/// The battle royale safe zone. Like everything else, it wraps around the
+/// edges of the world.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SafeZone {
+    pub center: Vec2,
+    pub radius: f32,
+}
+
+impl SafeZone {
+    pub fn contains(&self, pos: Vec2) -> bool {
+        distance_squared_wrapped(pos, self.center) <= self.radius * self.radius
+    }
+}
+
+/// Stage of a room's match. Rooms cycle through warmup, the match itself, a
+/// short pause once it ends and an intermission showing the results.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum MatchPhase {
+    /// Free play; scores and waves are reset when the match starts.
+    Warmup,
+    #[default]
+    InProgress,
+    /// The match is over. Ships still fly, but nobody can shoot or be hurt.
+    Ended,
+    /// Like `Ended`, while clients show the results.
+    Intermission,
+}
+
+impl MatchPhase {
+    /// Whether ships can shoot, score and die.
+    pub fn is_live(self) -> bool {
+        matches!(self, MatchPhase::Warmup | MatchPhase::InProgress)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MatchInfo {
+    pub phase: MatchPhase,
+    /// Seconds until the phase ends; `None` for a match without a time limit.
+    pub countdown: Option<f32>,
+    /// Score that wins the match: a player's in free-for-all, a team's in
+    /// team modes and the combined score in co-op.
+    pub score_limit: Option<u32>,
+}
+
+/// A player's final score in a match.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Standing {
+    pub id: u64,
+    pub name: String,
+    pub score: u32,
+    #[serde(default)]
+    pub team: Option<u8>,
+}
+
+/// Points a team's players have earned this match. Unlike player scores,
+/// these are not cut when a player dies or leaves; they start over from zero
+/// when the next match starts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TeamScore {
+    pub team: u8,
+    pub score: u32,
+}
+
+/// A room as listed in the lobby.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoomInfo {
+    pub id: u64,
+    pub name: String,
+    pub players: u32,
+    pub max_players: u32,
+    #[serde(default)]
+    pub mode: GameMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ClientMsg {
+    /// First message on a connection, always sent as JSON, announcing what
+    /// the client speaks. Followed by `Join`.
+    Hello {
+        protocol_version: u32,
+        client_version: String,
+        capabilities: Capabilities,
+    },
+    /// Always sent as JSON, right after `Hello`.
     Join { name: String },
     Input(PlayerInput),
     Chat { text: String },
     Ping { nonce: u64 },
+    /// Answer to a `ServerMsg::Ping`, echoing its nonce.
+    Pong { nonce: u64 },
+    /// Confirms the client holds the snapshot for `tick`, so later states can
+    /// be sent as deltas against it.
+    Ack { tick: u64 },
+    /// Asks for a fresh `RoomList`.
+    ListRooms,
+    /// Creates a room and moves the client into it.
+    CreateRoom {
+        name: String,
+        #[serde(default)]
+        mode: GameMode,
+    },
+    /// Moves the client into a room, leaving its current one.
+    JoinRoom { room_id: u64 },
+    /// Goes back to the lobby.
+    LeaveRoom,
+    /// Creates a room that is left out of `RoomList` and moves the client
+    /// into it. Others get in with its invite code or, if one is set, with
+    /// its name and `password`.
+    CreatePrivateRoom {
+        name: String,
+        password: Option<String>,
+        #[serde(default)]
+        mode: GameMode,
+    },
+    /// Joins the private room called `name`.
+    JoinPrivateRoom { name: String, password: String },
+    /// Joins the private room with invite code `code`.
+    JoinByInvite { code: String },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ServerMsg {
-    Welcome { id: u64, tick_hz: u32 },
-    State {
-        players: Vec<PlayerState>,
-        asteroids: Vec<AsteroidState>,
-        bullets: Vec<BulletState>,
+    /// Reply to `Join`, always sent as JSON. `capabilities` are the ones both
+    /// sides support; both switch to `encoding` right after this message.
+    Welcome {
+        id: u64,
+        tick_hz: u32,
+        #[serde(default)]
+        protocol_version: u32,
         #[serde(default)]
-        power_ups: Vec<PowerUpState>,
+        server_version: String,
         #[serde(default)]
-        wave: Option<WaveInfo>,
+        capabilities: Capabilities,
+        #[serde(default)]
+        encoding: Encoding,
+        /// Token for the UDP channel, if it was negotiated.
+        #[serde(default)]
+        udp_token: Option<u64>,
     },
+    /// Sent as JSON instead of `Welcome` when the server will not serve this
+    /// client; the server closes the connection afterwards.
+    Rejected { reason: String },
+    /// Full world state (a keyframe).
+    State(Snapshot),
+    /// World state relative to a snapshot the client acknowledged.
+    Delta(Box<SnapshotDelta>),
     Chat { from: String, text: String },
     System { text: String },
     Pong { nonce: u64 },
+    /// Round trip probe; the client answers right away with a
+    /// `ClientMsg::Pong` so the server can lag compensate its shots.
+    Ping { nonce: u64 },
+    /// Rooms open on the server, sent on entering the lobby and on request.
+    RoomList { rooms: Vec<RoomInfo> },
+    /// The client is now playing in `room`; states that follow belong to it.
+    RoomJoined { room: RoomInfo },
+    /// The client is back in the lobby.
+    RoomLeft,
+    /// The room's match is over. `standings` are sorted best first; `teams`
+    /// and `coop` are set in those modes, and `wave` is the wave reached.
+    MatchEnded {
+        standings: Vec<Standing>,
+        #[serde(default)]
+        teams: Vec<TeamScore>,
+        #[serde(default)]
+        coop: Option<CoopInfo>,
+        #[serde(default)]
+        wave: u32,
+    },
 }
 
 pub fn wrap_position(mut p: Vec2) -> Vec2 {
@@ -137,3 +495,21 @@ pub fn wrap_position(mut p: Vec2) -> Vec2 {
     }
     p
 }
+
+/// Signed distance from `b` to `a` along an axis that wraps every `wrap` units.
+pub fn shortest_delta(a: f32, b: f32, wrap: f32) -> f32 {
+    let d = a - b;
+    if d > wrap / 2.0 {
+        d - wrap
+    } else if d < -wrap / 2.0 {
+        d + wrap
+    } else {
+        d
+    }
+}
+
+pub fn distance_squared_wrapped(a: Vec2, b: Vec2) -> f32 {
+    let dx = shortest_delta(a.x, b.x, WORLD_WIDTH);
+    let dy = shortest_delta(a.y, b.y, WORLD_HEIGHT);
+    dx * dx + dy * dy
+}
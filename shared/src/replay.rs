@@ -0,0 +1,84 @@
+//! Recorded matches.
+//!
+//! A replay file opens with a [`ReplayHeader`] on one line of JSON, like a
+//! connection's handshake, followed by binary frames (see [`codec`]) holding
+//! the [`ServerMsg`]s a player in the room would have received, in order:
+//! the state of every tick, and the room's `Chat`, `System` and `MatchEnded`
+//! messages. States are a full `State` keyframe every
+//! [`KEYFRAME_INTERVAL_TICKS`] and otherwise a `Delta` against the tick before,
+//! so playback can start at any keyframe.
+
+use serde::{Deserialize, Serialize};
+
+use crate::codec::{self, CodecError, Encoding};
+use crate::{GameMode, ServerMsg, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Marks a file as a replay in its header.
+pub const REPLAY_FORMAT: &str = "rusted-asteroids replay";
+/// A full state is recorded this often (5 s).
+pub const KEYFRAME_INTERVAL_TICKS: u64 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplayHeader {
+    /// Always [`REPLAY_FORMAT`].
+    pub format: String,
+    /// Protocol version of the recording server; decides the binary layout of
+    /// the frames that follow.
+    pub protocol_version: u32,
+    /// Seed of the room's world.
+    pub seed: u64,
+    pub tick_hz: u32,
+    pub room: String,
+    pub mode: GameMode,
+    /// When recording started, in seconds since the Unix epoch.
+    pub started_at: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("not a replay file")]
+    NotAReplay,
+    #[error("replay was recorded with protocol version {0}, which this build cannot read")]
+    UnsupportedVersion(u32),
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+}
+
+/// Frame for the start of a replay file.
+pub fn encode_header(header: &ReplayHeader) -> Result<Vec<u8>, CodecError> {
+    codec::encode(Encoding::Json, header)
+}
+
+/// Frame for one message of a replay.
+pub fn encode_msg(msg: &ServerMsg) -> Result<Vec<u8>, CodecError> {
+    codec::encode(Encoding::Binary, msg)
+}
+
+/// Splits a replay file into its header and messages. A frame cut short at
+/// the end, as left by a server that stopped mid-write, is dropped.
+pub fn decode(bytes: &[u8]) -> Result<(ReplayHeader, Vec<ServerMsg>), ReplayError> {
+    let header_len = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or(ReplayError::NotAReplay)?;
+    let header: ReplayHeader =
+        codec::decode(Encoding::Json, &bytes[..header_len]).map_err(|_| ReplayError::NotAReplay)?;
+    if header.format != REPLAY_FORMAT {
+        return Err(ReplayError::NotAReplay);
+    }
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&header.protocol_version) {
+        return Err(ReplayError::UnsupportedVersion(header.protocol_version));
+    }
+
+    let mut msgs = Vec::new();
+    let mut rest = &bytes[header_len + 1..];
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let Some(payload) = rest.get(4..4 + len) else {
+            break;
+        };
+        msgs.push(codec::decode(Encoding::Binary, payload)?);
+        rest = &rest[4 + len..];
+    }
+    Ok((header, msgs))
+}
@@ -0,0 +1,2387 @@
+//! Headless game simulation.
+//!
+//! [`World`] owns every entity in the arena and advances it with
+//! [`World::step`]. It never looks at the wall clock or a global RNG: time is
+//! counted in ticks that only move when the caller steps the world, and all
+//! randomness comes from an RNG seeded at construction. Two worlds created with
+//! the same seed and fed the same inputs stay bit-identical, which
+//! [`World::checksum`] can be used to verify.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::snapshot::Snapshot;
+use crate::{
+    distance_squared_wrapped, shortest_delta, wrap_position, AsteroidKind, AsteroidState,
+    BossState, BulletState, CoopInfo, GameMode, MatchInfo, MatchPhase, PlayerEffects, PlayerInput,
+    PlayerState, PowerUpKind, PowerUpState, SafeZone, Standing, TeamScore, UfoState, Vec2,
+    WaveInfo, WORLD_HEIGHT, WORLD_WIDTH,
+};
+
+/// Rate the world is designed to be stepped at. Every timer in the world is
+/// counted in ticks of this rate.
+pub const TICK_HZ: u32 = 20;
+pub const TICK_DT: f32 = 1.0 / TICK_HZ as f32;
+
+pub const THRUST: f32 = 12.0;
+pub const DRAG: f32 = 0.985;
+pub const MAX_SPEED: f32 = 25.0;
+pub const BULLET_SPEED: f32 = 30.0;
+pub const FIRE_COOLDOWN: f32 = 0.2;
+pub const BULLET_TTL: f32 = 2.5;
+pub const ASTEROID_COUNT: usize = 50;
+
+// Gameplay constants
+pub const MAX_HEALTH: f32 = 100.0;
+pub const BULLET_DAMAGE: f32 = 25.0;
+/// An asteroid hit deals `asteroid_radius(size) * (ASTEROID_BASE_DAMAGE +
+/// relative speed * ASTEROID_SPEED_DAMAGE)`.
+pub const ASTEROID_BASE_DAMAGE: f32 = 4.0;
+pub const ASTEROID_SPEED_DAMAGE: f32 = 1.0;
+/// Ships are knocked clear of an asteroid they hit, at least this fast, and
+/// take no further asteroid damage for a moment.
+pub const ASTEROID_KNOCKBACK_SPEED: f32 = 8.0;
+pub const ASTEROID_HIT_COOLDOWN_SECS: f32 = 0.5;
+/// Damage a shield power-up absorbs before it breaks.
+pub const SHIELD_STRENGTH: f32 = 75.0;
+pub const SPAWN_INVINCIBILITY_SECS: f32 = 2.5;
+pub const RESPAWN_DELAY_SECS: f32 = 1.5;
+pub const SAFE_SPAWN_RADIUS: f32 = 8.0;
+pub const POWERUP_SPAWN_CHANCE: f32 = 0.3;
+pub const POWERUP_DURATION_SECS: f32 = 8.0;
+pub const POWERUP_TTL_SECS: f32 = 15.0;
+pub const POWERUP_RADIUS: f32 = 1.5;
+pub const COMBO_TIMEOUT_SECS: f32 = 3.0;
+pub const MAX_COMBO: u32 = 10;
+pub const KILL_STREAK_BONUS_INTERVAL: u32 = 3;
+pub const KILL_STREAK_BONUS_POINTS: u32 = 100;
+pub const DEATH_PENALTY_PERCENT: f32 = 0.15;
+pub const RAPID_FIRE_COOLDOWN_MULT: f32 = 0.4;
+pub const SPEED_BOOST_MULT: f32 = 1.5;
+pub const WAVE_COUNTDOWN_SECS: f32 = 3.0;
+pub const ASTEROIDS_PER_WAVE: usize = 5;
+pub const MAX_ASTEROIDS: usize = 100;
+/// Chance of each special asteroid kind at wave 1, how much it grows every
+/// wave after that, and where it stops growing.
+pub const SPECIAL_ASTEROID_BASE_CHANCE: f32 = 0.02;
+pub const SPECIAL_ASTEROID_CHANCE_PER_WAVE: f32 = 0.02;
+pub const SPECIAL_ASTEROID_MAX_CHANCE: f32 = 0.15;
+pub const EXPLOSION_RADIUS: f32 = 10.0;
+pub const EXPLOSION_DAMAGE: f32 = 40.0;
+/// Magnetic asteroids accelerate toward the nearest ship within range, up
+/// to a top speed.
+pub const MAGNET_RANGE: f32 = 40.0;
+pub const MAGNET_ACCEL: f32 = 3.0;
+pub const MAGNET_MAX_SPEED: f32 = 8.0;
+pub const CLUSTER_FRAGMENTS: u32 = 6;
+/// Every this many waves, a boss joins the asteroids. The wave only ends once
+/// it is destroyed.
+pub const BOSS_WAVE_INTERVAL: u32 = 5;
+/// Each boss has `BOSS_HEALTH` plus `BOSS_HEALTH_PER_BOSS` for every boss
+/// before it.
+pub const BOSS_HEALTH: f32 = 1000.0;
+pub const BOSS_HEALTH_PER_BOSS: f32 = 500.0;
+pub const BOSS_RADIUS: f32 = 6.0;
+pub const BOSS_SPEED: f32 = 4.0;
+pub const BOSS_CONTACT_DAMAGE: f32 = 30.0;
+/// Bosses cycle through a bullet ring, an aimed volley and launching small
+/// asteroids, attacking faster once below half health.
+pub const BOSS_ATTACK_INTERVAL_SECS: f32 = 2.0;
+pub const BOSS_ENRAGED_INTERVAL_MULT: f32 = 0.6;
+pub const BOSS_RING_BULLETS: u32 = 12;
+pub const BOSS_VOLLEY_BULLETS: u32 = 3;
+pub const BOSS_MINIONS: u32 = 3;
+pub const BOSS_BULLET_SPEED: f32 = 18.0;
+pub const BOSS_HIT_POINTS: u32 = 20;
+pub const BOSS_KILL_POINTS: u32 = 2000;
+/// UFOs turn up every `UFO_SPAWN_INTERVAL_SECS`, less `UFO_SPAWN_INTERVAL_STEP_SECS`
+/// per wave down to `UFO_MIN_SPAWN_INTERVAL_SECS`, while a wave is on. There
+/// is room for one more every 3 waves, up to `MAX_UFOS`.
+pub const UFO_SPAWN_INTERVAL_SECS: f32 = 25.0;
+pub const UFO_SPAWN_INTERVAL_STEP_SECS: f32 = 2.0;
+pub const UFO_MIN_SPAWN_INTERVAL_SECS: f32 = 8.0;
+pub const MAX_UFOS: usize = 4;
+/// UFOs appear at least this far from every ship.
+pub const UFO_SPAWN_CLEARANCE: f32 = 40.0;
+pub const UFO_HEALTH: f32 = 50.0;
+pub const UFO_RADIUS: f32 = 2.0;
+pub const UFO_ACCEL: f32 = 8.0;
+pub const UFO_MAX_SPEED: f32 = 10.0;
+/// UFOs fire at the ship they are hunting once it is in range, never quite
+/// dead on.
+pub const UFO_FIRE_INTERVAL_SECS: f32 = 1.5;
+pub const UFO_FIRE_RANGE: f32 = 50.0;
+pub const UFO_AIM_ERROR: f32 = 0.15;
+pub const UFO_BULLET_SPEED: f32 = 20.0;
+/// Damage a UFO deals when it rams a ship, destroying itself.
+pub const UFO_CONTACT_DAMAGE: f32 = 20.0;
+pub const UFO_KILL_POINTS: u32 = 300;
+/// Points for every bullet that hits an asteroid, on top of the points for
+/// destroying it.
+pub const ASTEROID_HIT_POINTS: u32 = 10;
+/// Number of teams in team modes.
+pub const TEAM_COUNT: u8 = 2;
+pub const PLAYER_KILL_POINTS: u32 = 200;
+/// Deaths a co-op room can take before the run, and with it the match, ends.
+pub const COOP_LIVES: u32 = 10;
+pub const WARMUP_SECS: f32 = 15.0;
+pub const MATCH_END_SECS: f32 = 3.0;
+pub const INTERMISSION_SECS: f32 = 10.0;
+/// Battle royale safe zone: it starts out covering the whole world, holds
+/// still for a while, then closes in on its centre.
+pub const ZONE_START_RADIUS: f32 = 130.0;
+pub const ZONE_END_RADIUS: f32 = 12.0;
+pub const ZONE_HOLD_SECS: f32 = 30.0;
+pub const ZONE_SHRINK_SECS: f32 = 150.0;
+/// Damage taken outside the zone.
+pub const ZONE_DAMAGE_PER_SEC: f32 = 20.0;
+
+/// Clients draw remote entities this far behind the newest snapshot they
+/// hold, so that there is almost always a pair to interpolate between.
+pub const INTERPOLATION_DELAY_SECS: f32 = 0.1;
+/// Furthest back a shooter's PvP targets are rewound, however high their ping.
+pub const MAX_LAG_COMPENSATION_SECS: f32 = 0.5;
+
+// Collision radii
+pub const PLAYER_RADIUS: f32 = 1.5;
+pub const BULLET_RADIUS: f32 = 0.5;
+
+pub fn asteroid_radius(size: u8) -> f32 {
+    match size {
+        1 => 2.0,
+        2 => 3.0,
+        _ => 4.0,
+    }
+}
+
+/// Bullets it takes to break an asteroid of `size`.
+pub fn asteroid_hit_points(size: u8) -> u8 {
+    match size {
+        1 => 1,
+        2 => 2,
+        _ => 4,
+    }
+}
+
+/// Applies one tick of steering, thrust, drag and the speed cap to a living
+/// ship. The server simulation and the client's local prediction both move
+/// ships through this function so they integrate identically.
+pub fn apply_movement(player: &mut PlayerState, input: &PlayerInput, speed_boost: bool, dt: f32) {
+    // 8-way directional: snap to target angle if provided
+    if let Some(target) = input.target_angle {
+        player.angle = target;
+    }
+
+    let thrust_mult = if speed_boost { SPEED_BOOST_MULT } else { 1.0 };
+    if input.thrust {
+        let dir = Vec2::new(player.angle.cos(), player.angle.sin());
+        player.vel = player.vel.add(dir.scale(THRUST * thrust_mult * dt));
+    }
+
+    player.vel = player.vel.scale(DRAG);
+    let max_speed = if speed_boost {
+        MAX_SPEED * SPEED_BOOST_MULT
+    } else {
+        MAX_SPEED
+    };
+    let speed_sq = player.vel.x * player.vel.x + player.vel.y * player.vel.y;
+    if speed_sq > max_speed * max_speed {
+        let scale = max_speed / speed_sq.sqrt();
+        player.vel = player.vel.scale(scale);
+    }
+    player.pos = wrap_position(player.pos.add(player.vel.scale(dt)));
+}
+
+/// Rounds `angle` to the nearest of the 8 directions ships move and fire in.
+pub fn snap_angle(angle: f32) -> f32 {
+    let sector = ((angle + std::f32::consts::FRAC_PI_8) / std::f32::consts::FRAC_PI_4).floor();
+    sector * std::f32::consts::FRAC_PI_4
+}
+
+/// Converts a duration in seconds to a whole number of ticks.
+pub fn secs_to_ticks(secs: f32) -> u64 {
+    (secs * TICK_HZ as f32).round() as u64
+}
+
+/// Seconds left until `until`, or `None` once it has passed.
+fn remaining(until: Option<u64>, now: u64) -> Option<f32> {
+    until.and_then(|t| {
+        if t > now {
+            Some((t - now) as f32 / TICK_HZ as f32)
+        } else {
+            None
+        }
+    })
+}
+
+fn active(until: Option<u64>, now: u64) -> bool {
+    until.is_some_and(|t| now < t)
+}
+
+/// Per-player bookkeeping that is not sent to clients.
+///
+/// All deadlines are tick numbers (see [`World::tick`]).
+#[derive(Clone)]
+struct PlayerRuntime {
+    last_fire: u64,
+    invincible_until: Option<u64>,
+    respawn_at: Option<u64>,
+    last_kill_time: Option<u64>,
+    combo: u32,
+    kill_streak: u32,
+    /// How many ticks behind the server this player sees other ships.
+    lag_ticks: u64,
+    shield_until: Option<u64>,
+    /// What is left of the shield while `shield_until` is active.
+    shield_hp: f32,
+    /// Until when the ship is immune to asteroids after hitting one.
+    asteroid_immune_until: Option<u64>,
+    rapid_fire_until: Option<u64>,
+    triple_shot_until: Option<u64>,
+    speed_boost_until: Option<u64>,
+    /// When the player lost their only life in a battle royale match.
+    eliminated_at: Option<u64>,
+    /// Joined a battle royale match under way and sits it out. Spectators
+    /// neither keep the match going nor place in it.
+    spectating: bool,
+}
+
+impl PlayerRuntime {
+    fn new(now: u64) -> Self {
+        Self {
+            last_fire: now,
+            invincible_until: None,
+            respawn_at: None,
+            last_kill_time: None,
+            combo: 0,
+            kill_streak: 0,
+            lag_ticks: 0,
+            shield_until: None,
+            shield_hp: 0.0,
+            asteroid_immune_until: None,
+            rapid_fire_until: None,
+            triple_shot_until: None,
+            speed_boost_until: None,
+            eliminated_at: None,
+            spectating: false,
+        }
+    }
+
+    fn is_invincible(&self, now: u64) -> bool {
+        active(self.invincible_until, now)
+    }
+
+    fn shield(&self, now: u64) -> f32 {
+        if active(self.shield_until, now) {
+            self.shield_hp
+        } else {
+            0.0
+        }
+    }
+
+    fn has_rapid_fire(&self, now: u64) -> bool {
+        active(self.rapid_fire_until, now)
+    }
+
+    fn has_triple_shot(&self, now: u64) -> bool {
+        active(self.triple_shot_until, now)
+    }
+
+    fn has_speed_boost(&self, now: u64) -> bool {
+        active(self.speed_boost_until, now)
+    }
+
+    fn get_effects(&self, now: u64) -> PlayerEffects {
+        PlayerEffects {
+            shield_remaining: remaining(self.shield_until, now),
+            rapid_fire_remaining: remaining(self.rapid_fire_until, now),
+            triple_shot_remaining: remaining(self.triple_shot_until, now),
+            speed_boost_remaining: remaining(self.speed_boost_until, now),
+            invincible_remaining: remaining(self.invincible_until, now),
+        }
+    }
+}
+
+struct PowerUpRuntime {
+    state: PowerUpState,
+    expires_at: u64,
+}
+
+/// Who fired a bullet. Enemy and player ids are handed out separately and can
+/// be equal, so a bullet's `owner_id` alone cannot tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BulletOwner {
+    Player(u64),
+    /// The boss or a UFO. Their bullets are only a danger to ships.
+    Enemy,
+}
+
+impl BulletOwner {
+    fn player(self) -> Option<u64> {
+        match self {
+            BulletOwner::Player(id) => Some(id),
+            BulletOwner::Enemy => None,
+        }
+    }
+}
+
+struct BulletRuntime {
+    state: BulletState,
+    expires_at: u64,
+}
+
+impl BulletRuntime {
+    fn owner(&self) -> BulletOwner {
+        if self.state.enemy {
+            BulletOwner::Enemy
+        } else {
+            BulletOwner::Player(self.state.owner_id)
+        }
+    }
+}
+
+/// What ends a match. Matches always start with a warmup; one without any
+/// limit only ends if a co-op room runs out of lives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchRules {
+    pub time_limit_secs: Option<u32>,
+    /// See [`MatchInfo::score_limit`].
+    pub score_limit: Option<u32>,
+}
+
+struct Ufo {
+    state: UfoState,
+    next_fire_at: u64,
+}
+
+struct Boss {
+    state: BossState,
+    next_attack_at: u64,
+    attacks: u32,
+}
+
+/// The battle royale zone of the current match.
+struct Zone {
+    center: Vec2,
+    started_at: u64,
+}
+
+struct CoopRun {
+    lives: u32,
+    score: u32,
+}
+
+impl CoopRun {
+    fn new() -> Self {
+        Self {
+            lives: COOP_LIVES,
+            score: 0,
+        }
+    }
+}
+
+/// The complete state of one arena.
+pub struct World {
+    seed: u64,
+    mode: GameMode,
+    /// ChaCha rather than `StdRng`, whose output may change between rand
+    /// versions and platforms and would break old seeds and replays.
+    rng: ChaCha8Rng,
+    next_id: u64,
+    tick: u64,
+    players: BTreeMap<u64, PlayerState>,
+    runtime: BTreeMap<u64, PlayerRuntime>,
+    bullets: Vec<BulletRuntime>,
+    asteroids: Vec<AsteroidState>,
+    power_ups: Vec<PowerUpRuntime>,
+    current_wave: u32,
+    wave_countdown: Option<u64>,
+    /// Points earned per team; empty outside team modes.
+    team_scores: BTreeMap<u8, u32>,
+    coop: Option<CoopRun>,
+    rules: MatchRules,
+    phase: MatchPhase,
+    /// Tick the current phase ends at; `None` while a match without a time
+    /// limit is in progress.
+    phase_ends_at: Option<u64>,
+    /// Only set while a battle royale match is in progress or just ended.
+    zone: Option<Zone>,
+    boss: Option<Boss>,
+    ufos: Vec<Ufo>,
+    next_ufo_at: u64,
+    /// Where each living ship was at the end of recent ticks, oldest first,
+    /// for rewinding PvP hits.
+    position_history: VecDeque<(u64, BTreeMap<u64, Vec2>)>,
+}
+
+impl World {
+    /// Creates a free-for-all world at wave 1 with a fresh asteroid field
+    /// generated from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self::with_mode(seed, GameMode::default())
+    }
+
+    /// Like [`World::new`], played under `mode`. The match never ends on
+    /// time or score.
+    pub fn with_mode(seed: u64, mode: GameMode) -> Self {
+        Self::with_rules(seed, mode, MatchRules::default())
+    }
+
+    /// Like [`World::with_mode`], with matches that end by `rules`. The world
+    /// starts in warmup.
+    pub fn with_rules(seed: u64, mode: GameMode, rules: MatchRules) -> Self {
+        let team_scores = if mode.has_teams() {
+            (0..TEAM_COUNT).map(|team| (team, 0)).collect()
+        } else {
+            BTreeMap::new()
+        };
+        let mut world = Self {
+            seed,
+            mode,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            next_id: 1,
+            tick: 0,
+            players: BTreeMap::new(),
+            runtime: BTreeMap::new(),
+            bullets: Vec::new(),
+            asteroids: Vec::new(),
+            power_ups: Vec::new(),
+            current_wave: 1,
+            wave_countdown: None,
+            team_scores,
+            coop: (mode == GameMode::Coop).then(CoopRun::new),
+            rules,
+            phase: MatchPhase::Warmup,
+            phase_ends_at: Some(secs_to_ticks(WARMUP_SECS)),
+            zone: None,
+            boss: None,
+            ufos: Vec::new(),
+            next_ufo_at: secs_to_ticks(UFO_SPAWN_INTERVAL_SECS),
+            position_history: VecDeque::new(),
+        };
+        world.asteroids = world.spawn_asteroids(ASTEROID_COUNT);
+        world
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Number of steps taken since the world was created.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn mode(&self) -> GameMode {
+        self.mode
+    }
+
+    pub fn team_scores(&self) -> Vec<TeamScore> {
+        self.team_scores
+            .iter()
+            .map(|(&team, &score)| TeamScore { team, score })
+            .collect()
+    }
+
+    pub fn coop_info(&self) -> Option<CoopInfo> {
+        self.coop.as_ref().map(|coop| CoopInfo {
+            lives: coop.lives,
+            score: coop.score,
+        })
+    }
+
+    pub fn match_phase(&self) -> MatchPhase {
+        self.phase
+    }
+
+    pub fn match_info(&self) -> MatchInfo {
+        MatchInfo {
+            phase: self.phase,
+            countdown: self
+                .phase_ends_at
+                .map(|t| remaining(Some(t), self.tick).unwrap_or(0.0)),
+            score_limit: self.rules.score_limit,
+        }
+    }
+
+    /// The battle royale zone as it is this tick.
+    pub fn safe_zone(&self) -> Option<SafeZone> {
+        self.zone.as_ref().map(|zone| {
+            let hold = secs_to_ticks(ZONE_HOLD_SECS);
+            let shrink = secs_to_ticks(ZONE_SHRINK_SECS);
+            let elapsed = self.tick.saturating_sub(zone.started_at + hold);
+            let t = (elapsed as f32 / shrink as f32).min(1.0);
+            SafeZone {
+                center: zone.center,
+                radius: ZONE_START_RADIUS + (ZONE_END_RADIUS - ZONE_START_RADIUS) * t,
+            }
+        })
+    }
+
+    /// Every player's score, best first. In battle royale, players are
+    /// ranked by how long they survived instead, with score breaking ties.
+    pub fn standings(&self) -> Vec<Standing> {
+        let mut standings: Vec<Standing> = self
+            .players
+            .values()
+            .filter(|p| !self.is_spectating(p.id))
+            .map(|p| Standing {
+                id: p.id,
+                name: p.name.clone(),
+                score: p.score,
+                team: p.team,
+            })
+            .collect();
+        standings.sort_by_key(|s| std::cmp::Reverse(s.score));
+        if self.mode == GameMode::BattleRoyale {
+            standings.sort_by_key(|s| {
+                let alive = self.players.get(&s.id).is_some_and(|p| p.alive);
+                let eliminated_at = self.runtime.get(&s.id).and_then(|rt| rt.eliminated_at);
+                std::cmp::Reverse((alive, eliminated_at))
+            });
+        }
+        standings
+    }
+
+    pub fn current_wave(&self) -> u32 {
+        self.current_wave
+    }
+
+    pub fn player(&self, id: u64) -> Option<&PlayerState> {
+        self.players.get(&id)
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &PlayerState> {
+        self.players.values()
+    }
+
+    pub fn asteroids(&self) -> &[AsteroidState] {
+        &self.asteroids
+    }
+
+    pub fn bullets(&self) -> impl Iterator<Item = &BulletState> {
+        self.bullets.iter().map(|b| &b.state)
+    }
+
+    pub fn power_ups(&self) -> impl Iterator<Item = &PowerUpState> {
+        self.power_ups.iter().map(|p| &p.state)
+    }
+
+    pub fn ufos(&self) -> impl Iterator<Item = &UfoState> {
+        self.ufos.iter().map(|u| &u.state)
+    }
+
+    pub fn boss(&self) -> Option<&BossState> {
+        self.boss.as_ref().map(|b| &b.state)
+    }
+
+    pub fn wave_info(&self) -> WaveInfo {
+        WaveInfo {
+            wave_number: self.current_wave,
+            asteroids_remaining: self.asteroids.len() as u32,
+            countdown: self
+                .wave_countdown
+                .map(|t| remaining(Some(t), self.tick).unwrap_or(0.0)),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tick: self.tick,
+            players: self.players().cloned().collect(),
+            asteroids: self.asteroids.clone(),
+            bullets: self.bullets().cloned().collect(),
+            power_ups: self.power_ups().cloned().collect(),
+            ufos: self.ufos().cloned().collect(),
+            wave: Some(self.wave_info()),
+            teams: self.team_scores(),
+            coop: self.coop_info(),
+            match_info: Some(self.match_info()),
+            zone: self.safe_zone(),
+            boss: self.boss().cloned(),
+        }
+    }
+
+    /// FNV-1a hash over the simulation state. Two worlds that have diverged in
+    /// any position, velocity, score or entity id will (almost certainly)
+    /// produce different checksums, which makes it cheap to compare runs in
+    /// replays and lockstep tests.
+    pub fn checksum(&self) -> u64 {
+        let mut hash = Fnv::new();
+        hash.u64(self.tick);
+        hash.u64(self.next_id);
+        hash.u64(self.current_wave as u64);
+        for p in self.players.values() {
+            hash.u64(p.id);
+            hash.vec2(p.pos);
+            hash.vec2(p.vel);
+            hash.f32(p.angle);
+            hash.u64(p.alive as u64);
+            hash.u64(p.score as u64);
+            hash.u64(p.team.map_or(u64::MAX, u64::from));
+            hash.f32(p.health);
+            hash.f32(p.shield);
+        }
+        for (&team, &score) in &self.team_scores {
+            hash.u64(team as u64);
+            hash.u64(score as u64);
+        }
+        if let Some(coop) = &self.coop {
+            hash.u64(coop.lives as u64);
+            hash.u64(coop.score as u64);
+        }
+        hash.u64(self.phase as u64);
+        hash.u64(self.phase_ends_at.unwrap_or(u64::MAX));
+        if let Some(zone) = &self.zone {
+            hash.vec2(zone.center);
+            hash.u64(zone.started_at);
+        }
+        for a in &self.asteroids {
+            hash.u64(a.id);
+            hash.vec2(a.pos);
+            hash.vec2(a.vel);
+            hash.u64(a.size as u64);
+            hash.u64(a.damage as u64);
+            hash.u64(a.kind as u64);
+        }
+        for b in &self.bullets {
+            hash.u64(b.state.id);
+            hash.vec2(b.state.pos);
+        }
+        for p in &self.power_ups {
+            hash.u64(p.state.id);
+            hash.vec2(p.state.pos);
+        }
+        for u in &self.ufos {
+            hash.u64(u.state.id);
+            hash.vec2(u.state.pos);
+            hash.vec2(u.state.vel);
+            hash.f32(u.state.health);
+        }
+        hash.u64(self.next_ufo_at);
+        if let Some(boss) = &self.boss {
+            hash.u64(boss.state.id);
+            hash.vec2(boss.state.pos);
+            hash.f32(boss.state.health);
+            hash.u64(boss.next_attack_at);
+        }
+        hash.finish()
+    }
+
+    /// Adds a player at a safe spot with spawn invincibility, on the smallest
+    /// team in team modes. Does nothing if `id` is already in the world.
+    pub fn add_player(&mut self, id: u64, name: String) {
+        if self.players.contains_key(&id) {
+            return;
+        }
+        let mut player = spawn_player(id, name, &self.asteroids, &mut self.rng);
+        if self.mode.has_teams() {
+            player.team = (0..TEAM_COUNT).min_by_key(|&team| {
+                self.players
+                    .values()
+                    .filter(|p| p.team == Some(team))
+                    .count()
+            });
+        }
+        // Sits out the rest of a lost co-op run or a battle royale match
+        if self.coop.as_ref().is_some_and(|coop| coop.lives == 0) || self.single_life() {
+            player.alive = false;
+        }
+        self.players.insert(id, player);
+        let mut rt = PlayerRuntime::new(self.tick);
+        rt.spectating = self.single_life();
+        // Give spawn invincibility
+        rt.invincible_until = Some(self.tick + secs_to_ticks(SPAWN_INVINCIBILITY_SECS));
+        self.runtime.insert(id, rt);
+    }
+
+    /// Sets how far behind the server `id` sees the other ships: their round
+    /// trip time plus the interpolation delay. Bullets they fire are tested
+    /// against PvP targets where those targets were that long ago, capped at
+    /// [`MAX_LAG_COMPENSATION_SECS`].
+    pub fn set_player_latency(&mut self, id: u64, secs: f32) {
+        if let Some(rt) = self.runtime.get_mut(&id) {
+            rt.lag_ticks = secs_to_ticks(secs.clamp(0.0, MAX_LAG_COMPENSATION_SECS));
+        }
+    }
+
+    pub fn remove_player(&mut self, id: u64) -> Option<PlayerState> {
+        self.runtime.remove(&id);
+        self.players.remove(&id)
+    }
+
+    /// Advances the world by one tick of `dt` seconds.
+    ///
+    /// `dt` only scales movement; timers always count whole ticks, so callers
+    /// should step at [`TICK_HZ`] with [`TICK_DT`]. `inputs` holds the current
+    /// input of each player; players without an entry are treated as idle for
+    /// this step.
+    pub fn step(&mut self, inputs: &HashMap<u64, PlayerInput>, dt: f32) {
+        self.tick += 1;
+        let now = self.tick;
+        if self.phase_ends_at.is_some_and(|t| now >= t) {
+            self.next_match_phase();
+        }
+        let live = self.phase.is_live();
+        self.update_boss(live, dt);
+        self.update_ufos(live, dt);
+        let rng = &mut self.rng;
+
+        // Process respawn timers first
+        let mut players_to_respawn: Vec<u64> = Vec::new();
+        for (id, rt) in self.runtime.iter_mut() {
+            if let Some(respawn_at) = rt.respawn_at {
+                if now >= respawn_at {
+                    players_to_respawn.push(*id);
+                    rt.respawn_at = None;
+                }
+            }
+        }
+
+        // Respawn players whose timer expired
+        for id in players_to_respawn {
+            if let Some(player) = self.players.get_mut(&id) {
+                let safe_pos = find_safe_spawn_position(&self.asteroids, rng);
+                player.pos = safe_pos;
+                player.vel = Vec2::new(0.0, 0.0);
+                player.alive = true;
+                player.health = MAX_HEALTH;
+                player.respawn_timer = None;
+                if let Some(rt) = self.runtime.get_mut(&id) {
+                    rt.invincible_until = Some(now + secs_to_ticks(SPAWN_INVINCIBILITY_SECS));
+                }
+            }
+        }
+
+        // Update player state from runtime (effects, combo, streak)
+        for (id, player) in self.players.iter_mut() {
+            if let Some(rt) = self.runtime.get(id) {
+                player.effects = rt.get_effects(now);
+                player.shield = rt.shield(now);
+                player.combo = rt.combo;
+                player.kill_streak = rt.kill_streak;
+                if let Some(timer) = remaining(rt.respawn_at, now) {
+                    player.respawn_timer = Some(timer);
+                }
+            }
+        }
+
+        // Player movement and shooting
+        let player_ids: Vec<u64> = self.players.keys().cloned().collect();
+        for id in player_ids {
+            let (player_alive, has_rapid, has_triple, has_speed, last_fire) = {
+                let player = match self.players.get(&id) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let rt = match self.runtime.get(&id) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                (
+                    player.alive,
+                    rt.has_rapid_fire(now),
+                    rt.has_triple_shot(now),
+                    rt.has_speed_boost(now),
+                    rt.last_fire,
+                )
+            };
+
+            let input = inputs.get(&id).cloned().unwrap_or_default();
+
+            let player = self.players.get_mut(&id).unwrap();
+            // Acknowledge the input even while dead so clients can drop it
+            player.last_input_seq = input.seq;
+
+            if !player_alive {
+                continue;
+            }
+
+            apply_movement(player, &input, has_speed, dt);
+
+            // Shooting
+            if input.fire && live {
+                let cooldown = if has_rapid {
+                    FIRE_COOLDOWN * RAPID_FIRE_COOLDOWN_MULT
+                } else {
+                    FIRE_COOLDOWN
+                };
+                if now - last_fire >= secs_to_ticks(cooldown) {
+                    if let Some(rt) = self.runtime.get_mut(&id) {
+                        rt.last_fire = now;
+                    }
+
+                    // Snap angle to 8 directions
+                    let mut a = player.angle % std::f32::consts::TAU;
+                    if a < 0.0 {
+                        a += std::f32::consts::TAU;
+                    }
+                    let snapped_angle = snap_angle(a);
+
+                    // Create bullets (1 or 3 depending on triple shot)
+                    let angles = if has_triple {
+                        vec![snapped_angle - 0.2, snapped_angle, snapped_angle + 0.2]
+                    } else {
+                        vec![snapped_angle]
+                    };
+
+                    for angle in angles {
+                        let dir = Vec2::new(angle.cos(), angle.sin());
+                        let bullet_id = self.next_id;
+                        self.next_id += 1;
+                        self.bullets.push(BulletRuntime {
+                            state: BulletState {
+                                id: bullet_id,
+                                owner_id: id,
+                                enemy: false,
+                                pos: player.pos,
+                                vel: dir.scale(BULLET_SPEED),
+                            },
+                            expires_at: now + secs_to_ticks(BULLET_TTL),
+                        });
+                    }
+                }
+            }
+        }
+
+        // Update bullets
+        for bullet in &mut self.bullets {
+            bullet.state.pos = bullet.state.pos.add(bullet.state.vel.scale(dt));
+        }
+        self.bullets.retain(|b| {
+            now < b.expires_at
+                && b.state.pos.x >= 0.0
+                && b.state.pos.x <= WORLD_WIDTH
+                && b.state.pos.y >= 0.0
+                && b.state.pos.y <= WORLD_HEIGHT
+        });
+
+        // Update asteroids. Magnetic ones are pulled toward the nearest ship
+        for ast in &mut self.asteroids {
+            if ast.kind == AsteroidKind::Magnetic {
+                let nearest = self
+                    .players
+                    .values()
+                    .filter(|p| p.alive)
+                    .map(|p| (distance_squared_wrapped(p.pos, ast.pos), p.pos))
+                    .filter(|(dist_sq, _)| *dist_sq < MAGNET_RANGE * MAGNET_RANGE)
+                    .min_by(|a, b| a.0.total_cmp(&b.0));
+                if let Some((dist_sq, target)) = nearest.filter(|(dist_sq, _)| *dist_sq > 0.0) {
+                    let dist = dist_sq.sqrt();
+                    let pull = Vec2::new(
+                        shortest_delta(target.x, ast.pos.x, WORLD_WIDTH) / dist,
+                        shortest_delta(target.y, ast.pos.y, WORLD_HEIGHT) / dist,
+                    );
+                    ast.vel = ast.vel.add(pull.scale(MAGNET_ACCEL * dt));
+                    let speed = (ast.vel.x * ast.vel.x + ast.vel.y * ast.vel.y).sqrt();
+                    if speed > MAGNET_MAX_SPEED {
+                        ast.vel = ast.vel.scale(MAGNET_MAX_SPEED / speed);
+                    }
+                }
+            }
+            ast.pos = wrap_position(ast.pos.add(ast.vel.scale(dt)));
+        }
+
+        // Update power-ups (remove expired)
+        self.power_ups.retain(|p| now < p.expires_at);
+
+        // Collision: bullet-boss
+        let mut boss_hits: Vec<(u64, u64)> = Vec::new();
+        if let Some(boss) = &mut self.boss {
+            for bullet in &self.bullets {
+                let Some(shooter_id) = bullet.owner().player() else {
+                    continue;
+                };
+                if boss.state.health <= 0.0 {
+                    continue;
+                }
+                let dist_sq = distance_squared_wrapped(bullet.state.pos, boss.state.pos);
+                let radius_sum = BULLET_RADIUS + BOSS_RADIUS;
+                if dist_sq < radius_sum * radius_sum {
+                    boss.state.health = (boss.state.health - BULLET_DAMAGE).max(0.0);
+                    boss_hits.push((bullet.state.id, shooter_id));
+                }
+            }
+        }
+        for &(bullet_id, shooter_id) in &boss_hits {
+            self.bullets.retain(|b| b.state.id != bullet_id);
+            self.award_points(shooter_id, BOSS_HIT_POINTS);
+        }
+        if self.boss.as_ref().is_some_and(|b| b.state.health <= 0.0) {
+            let boss = self.boss.take().unwrap();
+            // The killing shot takes the bounty, and the wreck always drops a
+            // power-up
+            if let Some(&(_, shooter_id)) = boss_hits.last() {
+                self.award_points(shooter_id, BOSS_KILL_POINTS);
+            }
+            let kind = power_up_kind(&mut self.rng);
+            let id = self.next_entity_id();
+            self.power_ups.push(PowerUpRuntime {
+                state: PowerUpState {
+                    id,
+                    pos: boss.state.pos,
+                    kind,
+                },
+                expires_at: now + secs_to_ticks(POWERUP_TTL_SECS),
+            });
+        }
+
+        // Collision: bullet-UFO
+        let mut ufo_hits: Vec<(u64, u64)> = Vec::new();
+        let mut bullets_hit_ufos: Vec<u64> = Vec::new();
+        for ufo in &mut self.ufos {
+            for bullet in &self.bullets {
+                let Some(shooter_id) = bullet.owner().player() else {
+                    continue;
+                };
+                if ufo.state.health <= 0.0 || bullets_hit_ufos.contains(&bullet.state.id) {
+                    continue;
+                }
+                let dist_sq = distance_squared_wrapped(bullet.state.pos, ufo.state.pos);
+                let radius_sum = BULLET_RADIUS + UFO_RADIUS;
+                if dist_sq < radius_sum * radius_sum {
+                    ufo.state.health = (ufo.state.health - BULLET_DAMAGE).max(0.0);
+                    if ufo.state.health <= 0.0 {
+                        ufo_hits.push((ufo.state.id, shooter_id));
+                    }
+                    bullets_hit_ufos.push(bullet.state.id);
+                }
+            }
+        }
+        self.bullets.retain(|b| !bullets_hit_ufos.contains(&b.state.id));
+        for (ufo_id, shooter_id) in ufo_hits {
+            let Some(index) = self.ufos.iter().position(|u| u.state.id == ufo_id) else {
+                continue;
+            };
+            let ufo = self.ufos.remove(index);
+            self.award_points(shooter_id, UFO_KILL_POINTS);
+            if self.rng.gen::<f32>() < POWERUP_SPAWN_CHANCE {
+                let kind = power_up_kind(&mut self.rng);
+                let id = self.next_entity_id();
+                self.power_ups.push(PowerUpRuntime {
+                    state: PowerUpState {
+                        id,
+                        pos: ufo.state.pos,
+                        kind,
+                    },
+                    expires_at: now + secs_to_ticks(POWERUP_TTL_SECS),
+                });
+            }
+        }
+        let rng = &mut self.rng;
+
+        // Collision: bullet-asteroid
+        let mut bullets_to_remove: Vec<u64> = Vec::new();
+        let mut asteroids_to_remove: Vec<u64> = Vec::new();
+        let mut new_asteroids: Vec<AsteroidState> = Vec::new();
+        let mut explosions: Vec<Vec2> = Vec::new();
+
+        for bullet in &self.bullets {
+            let Some(shooter_id) = bullet.owner().player() else {
+                continue;
+            };
+            for ast in &mut self.asteroids {
+                if asteroids_to_remove.contains(&ast.id) {
+                    continue;
+                }
+                let dist_sq = distance_squared_wrapped(bullet.state.pos, ast.pos);
+                let radius_sum = BULLET_RADIUS + asteroid_radius(ast.size);
+                if dist_sq < radius_sum * radius_sum {
+                    bullets_to_remove.push(bullet.state.id);
+                    ast.damage += 1;
+                    let destroyed = ast.damage >= asteroid_hit_points(ast.size);
+                    let mut points = ASTEROID_HIT_POINTS;
+
+                    if destroyed {
+                        asteroids_to_remove.push(ast.id);
+
+                        // Award points with combo multiplier
+                        let base_points = match ast.size {
+                            1 => 100,
+                            2 => 50,
+                            _ => 20,
+                        };
+
+                        if let Some(rt) = self.runtime.get_mut(&shooter_id) {
+                            // Check combo timing
+                            let combo_active = rt
+                                .last_kill_time
+                                .is_some_and(|t| now - t < secs_to_ticks(COMBO_TIMEOUT_SECS));
+
+                            if combo_active {
+                                rt.combo = (rt.combo + 1).min(MAX_COMBO);
+                            } else {
+                                rt.combo = 1;
+                            }
+                            rt.last_kill_time = Some(now);
+
+                            let multiplier = rt.combo;
+                            points += base_points * multiplier;
+
+                            if let Some(player) = self.players.get_mut(&shooter_id) {
+                                player.combo = rt.combo;
+                            }
+                        }
+                    }
+
+                    if let Some(player) = self.players.get_mut(&shooter_id) {
+                        player.score += points;
+                        if let Some(team) = player.team {
+                            *self.team_scores.entry(team).or_default() += points;
+                        }
+                        if let Some(coop) = &mut self.coop {
+                            coop.score += points;
+                        }
+                    }
+
+                    if !destroyed {
+                        break;
+                    }
+
+                    // Explosive asteroids blow up instead of splitting, cluster
+                    // ones shatter, and the rest split with velocity
+                    // inheritance
+                    if ast.kind == AsteroidKind::Explosive {
+                        explosions.push(ast.pos);
+                    } else if ast.kind == AsteroidKind::Cluster {
+                        for i in 0..CLUSTER_FRAGMENTS {
+                            let angle = i as f32 * std::f32::consts::TAU / CLUSTER_FRAGMENTS as f32
+                                + rng.gen_range(-0.3..0.3);
+                            let dir = Vec2::new(angle.cos(), angle.sin());
+                            new_asteroids.push(AsteroidState {
+                                id: self.next_id,
+                                pos: wrap_position(ast.pos.add(dir)),
+                                vel: ast.vel.add(dir.scale(rng.gen_range(3.0..6.0))),
+                                size: 1,
+                                damage: 0,
+                                kind: AsteroidKind::Normal,
+                            });
+                            self.next_id += 1;
+                        }
+                    } else if ast.size > 1 {
+                        let new_size = ast.size - 1;
+                        for i in 0..2 {
+                            let spread_angle = rng.gen_range(-0.5..0.5);
+                            let parent_angle = ast.vel.y.atan2(ast.vel.x);
+                            let new_angle = parent_angle
+                                + spread_angle
+                                + if i == 0 {
+                                    std::f32::consts::FRAC_PI_4
+                                } else {
+                                    -std::f32::consts::FRAC_PI_4
+                                };
+                            let parent_speed =
+                                (ast.vel.x * ast.vel.x + ast.vel.y * ast.vel.y).sqrt();
+                            let new_speed = parent_speed * rng.gen_range(0.8..1.3) + 1.0;
+                            let offset_angle = if i == 0 {
+                                new_angle
+                            } else {
+                                new_angle + std::f32::consts::PI
+                            };
+                            new_asteroids.push(AsteroidState {
+                                id: self.next_id,
+                                pos: wrap_position(
+                                    ast.pos
+                                        .add(Vec2::new(offset_angle.cos(), offset_angle.sin())),
+                                ),
+                                vel: Vec2::new(
+                                    new_angle.cos() * new_speed,
+                                    new_angle.sin() * new_speed,
+                                ),
+                                size: new_size,
+                                damage: 0,
+                                kind: AsteroidKind::Normal,
+                            });
+                            self.next_id += 1;
+                        }
+                    }
+
+                    // Chance to spawn power-up
+                    if rng.gen::<f32>() < POWERUP_SPAWN_CHANCE {
+                        let kind = power_up_kind(rng);
+                        self.power_ups.push(PowerUpRuntime {
+                            state: PowerUpState {
+                                id: self.next_id,
+                                pos: ast.pos,
+                                kind,
+                            },
+                            expires_at: now + secs_to_ticks(POWERUP_TTL_SECS),
+                        });
+                        self.next_id += 1;
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        self.bullets
+            .retain(|b| !bullets_to_remove.contains(&b.state.id));
+        self.asteroids
+            .retain(|a| !asteroids_to_remove.contains(&a.id));
+        // Explosions destroy the asteroids around them outright and hurt any
+        // ship caught in the blast
+        for &center in &explosions {
+            self.asteroids.retain(|a| {
+                let reach = EXPLOSION_RADIUS + asteroid_radius(a.size);
+                distance_squared_wrapped(a.pos, center) >= reach * reach
+            });
+            let caught: Vec<u64> = self
+                .players
+                .values()
+                .filter(|p| p.alive && live)
+                .filter(|p| {
+                    let reach = EXPLOSION_RADIUS + PLAYER_RADIUS;
+                    distance_squared_wrapped(p.pos, center) < reach * reach
+                })
+                .filter(|p| {
+                    !self
+                        .runtime
+                        .get(&p.id)
+                        .is_some_and(|rt| rt.is_invincible(now))
+                })
+                .map(|p| p.id)
+                .collect();
+            for id in caught {
+                self.apply_damage(id, EXPLOSION_DAMAGE);
+            }
+        }
+        self.asteroids.extend(new_asteroids);
+
+        // Collision: player-power-up
+        let mut power_ups_to_remove: Vec<u64> = Vec::new();
+        for player in self.players.values() {
+            if !player.alive {
+                continue;
+            }
+            for pu in &self.power_ups {
+                let dist_sq = distance_squared_wrapped(player.pos, pu.state.pos);
+                let radius_sum = PLAYER_RADIUS + POWERUP_RADIUS;
+                if dist_sq < radius_sum * radius_sum {
+                    power_ups_to_remove.push(pu.state.id);
+                    if let Some(rt) = self.runtime.get_mut(&player.id) {
+                        let effect_end = now + secs_to_ticks(POWERUP_DURATION_SECS);
+                        match pu.state.kind {
+                            PowerUpKind::Shield => {
+                                rt.shield_until = Some(effect_end);
+                                rt.shield_hp = SHIELD_STRENGTH;
+                            }
+                            PowerUpKind::RapidFire => rt.rapid_fire_until = Some(effect_end),
+                            PowerUpKind::TripleShot => rt.triple_shot_until = Some(effect_end),
+                            PowerUpKind::SpeedBoost => rt.speed_boost_until = Some(effect_end),
+                        }
+                    }
+                }
+            }
+        }
+        self.power_ups
+            .retain(|p| !power_ups_to_remove.contains(&p.state.id));
+
+        // Collision: player-asteroid (check invincibility). The harder and
+        // bigger the asteroid, the more it hurts
+        let mut asteroid_hits: Vec<(u64, f32, Vec2)> = Vec::new();
+        for player in self.players.values() {
+            if !player.alive || !live {
+                continue;
+            }
+            let is_invincible = self.runtime.get(&player.id).is_some_and(|rt| {
+                rt.is_invincible(now) || active(rt.asteroid_immune_until, now)
+            });
+            if is_invincible {
+                continue;
+            }
+            for ast in &self.asteroids {
+                let dist_sq = distance_squared_wrapped(player.pos, ast.pos);
+                let radius_sum = PLAYER_RADIUS + asteroid_radius(ast.size);
+                if dist_sq < radius_sum * radius_sum {
+                    let rel_vel = Vec2::new(player.vel.x - ast.vel.x, player.vel.y - ast.vel.y);
+                    let rel_speed = (rel_vel.x * rel_vel.x + rel_vel.y * rel_vel.y).sqrt();
+                    let damage = asteroid_radius(ast.size)
+                        * (ASTEROID_BASE_DAMAGE + rel_speed * ASTEROID_SPEED_DAMAGE);
+                    // Push the ship straight away from the asteroid's centre
+                    let dist = dist_sq.sqrt();
+                    let away = if dist > 0.0 {
+                        Vec2::new(
+                            shortest_delta(player.pos.x, ast.pos.x, WORLD_WIDTH) / dist,
+                            shortest_delta(player.pos.y, ast.pos.y, WORLD_HEIGHT) / dist,
+                        )
+                    } else {
+                        Vec2::new(1.0, 0.0)
+                    };
+                    let knockback = ast
+                        .vel
+                        .add(away.scale(ASTEROID_KNOCKBACK_SPEED.max(rel_speed * 0.5)));
+                    asteroid_hits.push((player.id, damage, knockback));
+                    break;
+                }
+            }
+            // Ramming the boss hurts too
+            if let Some(boss) = &self.boss {
+                let dist_sq = distance_squared_wrapped(player.pos, boss.state.pos);
+                let radius_sum = PLAYER_RADIUS + BOSS_RADIUS;
+                let already_hit = asteroid_hits.last().is_some_and(|&(id, ..)| id == player.id);
+                if dist_sq < radius_sum * radius_sum && !already_hit {
+                    let dist = dist_sq.sqrt().max(0.001);
+                    let away = Vec2::new(
+                        shortest_delta(player.pos.x, boss.state.pos.x, WORLD_WIDTH) / dist,
+                        shortest_delta(player.pos.y, boss.state.pos.y, WORLD_HEIGHT) / dist,
+                    );
+                    let knockback = boss
+                        .state
+                        .vel
+                        .add(away.scale(ASTEROID_KNOCKBACK_SPEED * 2.0));
+                    asteroid_hits.push((player.id, BOSS_CONTACT_DAMAGE, knockback));
+                }
+            }
+        }
+
+        // Collision: player-UFO. The UFO is destroyed ramming the ship
+        let mut ufo_rams: Vec<(u64, u64)> = Vec::new();
+        for ufo in &self.ufos {
+            for player in self.players.values() {
+                if !player.alive || !live {
+                    continue;
+                }
+                let is_invincible = self
+                    .runtime
+                    .get(&player.id)
+                    .is_some_and(|rt| rt.is_invincible(now));
+                let dist_sq = distance_squared_wrapped(player.pos, ufo.state.pos);
+                let radius_sum = PLAYER_RADIUS + UFO_RADIUS;
+                if !is_invincible && dist_sq < radius_sum * radius_sum {
+                    ufo_rams.push((ufo.state.id, player.id));
+                    break;
+                }
+            }
+        }
+        for (ufo_id, player_id) in ufo_rams {
+            self.ufos.retain(|u| u.state.id != ufo_id);
+            self.apply_damage(player_id, UFO_CONTACT_DAMAGE);
+        }
+
+        // Apply asteroid damage
+        for (id, damage, knockback) in asteroid_hits {
+            if let Some(rt) = self.runtime.get_mut(&id) {
+                rt.asteroid_immune_until = Some(now + secs_to_ticks(ASTEROID_HIT_COOLDOWN_SECS));
+            }
+            if let Some(player) = self.players.get_mut(&id) {
+                player.vel = knockback;
+            }
+            self.apply_damage(id, damage);
+        }
+
+        // Safe zone: ships outside it take damage, however fresh their spawn
+        if let Some(zone) = self.safe_zone().filter(|_| live) {
+            let outside: Vec<u64> = self
+                .players
+                .values()
+                .filter(|p| p.alive && !zone.contains(p.pos))
+                .map(|p| p.id)
+                .collect();
+            for id in outside {
+                self.apply_damage(id, ZONE_DAMAGE_PER_SEC / TICK_HZ as f32);
+            }
+        }
+
+        // Collision: bullet-player (PvP, check invincibility). Targets are
+        // rewound to where the shooter saw them
+        let friendly_fire = matches!(
+            self.mode,
+            GameMode::TeamDeathmatch {
+                friendly_fire: true
+            }
+        );
+        let mut player_hits: Vec<(u64, Option<u64>)> = Vec::new();
+        let mut bullets_hit: Vec<u64> = Vec::new();
+        for bullet in &self.bullets {
+            let shooter_id = bullet.owner().player();
+            // Co-op has no PvP: players' bullets pass through other players
+            if self.mode == GameMode::Coop && shooter_id.is_some() {
+                continue;
+            }
+            let shooter_team = shooter_id
+                .and_then(|id| self.players.get(&id))
+                .and_then(|p| p.team);
+            let lag_ticks = shooter_id
+                .and_then(|id| self.runtime.get(&id))
+                .map_or(0, |rt| rt.lag_ticks);
+            let seen = self.positions_at(now.saturating_sub(lag_ticks));
+            for player in self.players.values() {
+                if !player.alive || Some(player.id) == shooter_id {
+                    continue;
+                }
+                // Bullets pass through teammates
+                if shooter_team.is_some() && player.team == shooter_team && !friendly_fire {
+                    continue;
+                }
+                let is_invincible = self
+                    .runtime
+                    .get(&player.id)
+                    .is_some_and(|rt| rt.is_invincible(now));
+                if is_invincible {
+                    continue;
+                }
+                let target_pos = seen
+                    .and_then(|positions| positions.get(&player.id))
+                    .copied()
+                    .unwrap_or(player.pos);
+                let dist_sq = distance_squared_wrapped(bullet.state.pos, target_pos);
+                let radius_sum = BULLET_RADIUS + PLAYER_RADIUS;
+                if dist_sq < radius_sum * radius_sum {
+                    bullets_hit.push(bullet.state.id);
+                    player_hits.push((player.id, shooter_id));
+                    break;
+                }
+            }
+        }
+
+        // Apply bullet damage; only the killing shot scores
+        for (victim_id, shooter_id) in player_hits {
+            let victim_team = self.players.get(&victim_id).and_then(|p| p.team);
+            let shooter_team = shooter_id
+                .and_then(|id| self.players.get(&id))
+                .and_then(|p| p.team);
+            let teamkill = victim_team.is_some() && victim_team == shooter_team;
+            if !self.apply_damage(victim_id, BULLET_DAMAGE) || teamkill {
+                continue;
+            }
+            let Some(shooter_id) = shooter_id else {
+                continue;
+            };
+
+            // Award kill streak
+            if let Some(rt) = self.runtime.get_mut(&shooter_id) {
+                rt.kill_streak += 1;
+                let streak_bonus = if rt.kill_streak % KILL_STREAK_BONUS_INTERVAL == 0 {
+                    KILL_STREAK_BONUS_POINTS
+                } else {
+                    0
+                };
+                if let Some(shooter) = self.players.get_mut(&shooter_id) {
+                    let points = PLAYER_KILL_POINTS + streak_bonus;
+                    shooter.score += points;
+                    shooter.kill_streak = rt.kill_streak;
+                    if let Some(team) = shooter.team {
+                        *self.team_scores.entry(team).or_default() += points;
+                    }
+                }
+            }
+        }
+        self.bullets.retain(|b| !bullets_hit.contains(&b.state.id));
+        self.record_positions();
+
+        if self.phase == MatchPhase::InProgress
+            && (self.score_limit_reached() || self.last_ship_standing())
+        {
+            self.end_match();
+        }
+
+        // Wave system: check if all asteroids, and any boss, are cleared
+        if self.asteroids.is_empty() && self.boss.is_none() && self.phase.is_live() {
+            if self.wave_countdown.is_none() {
+                // Start countdown for next wave
+                self.wave_countdown = Some(now + secs_to_ticks(WAVE_COUNTDOWN_SECS));
+            } else if let Some(countdown_end) = self.wave_countdown {
+                if now >= countdown_end {
+                    // Spawn next wave
+                    self.current_wave += 1;
+                    let mut asteroid_count =
+                        ASTEROID_COUNT + (self.current_wave as usize - 1) * ASTEROIDS_PER_WAVE;
+                    // Boss waves bring half the usual rocks
+                    let waves_since_boss = self.current_wave % BOSS_WAVE_INTERVAL;
+                    if waves_since_boss == 0 {
+                        self.spawn_boss();
+                        asteroid_count /= 2;
+                    }
+                    self.asteroids = self.spawn_asteroids(asteroid_count.min(MAX_ASTEROIDS));
+                    self.wave_countdown = None;
+                }
+            }
+        }
+    }
+
+    /// Ship positions at the end of `tick`, if that tick is still in the
+    /// history. `None` for the current tick, whose positions are live.
+    fn positions_at(&self, tick: u64) -> Option<&BTreeMap<u64, Vec2>> {
+        self.position_history
+            .iter()
+            .rev()
+            .find(|(t, _)| *t == tick)
+            .map(|(_, positions)| positions)
+    }
+
+    fn record_positions(&mut self) {
+        let positions = self
+            .players
+            .values()
+            .filter(|p| p.alive)
+            .map(|p| (p.id, p.pos))
+            .collect();
+        self.position_history.push_back((self.tick, positions));
+        while self.position_history.len() as u64 > secs_to_ticks(MAX_LAG_COMPENSATION_SECS) {
+            self.position_history.pop_front();
+        }
+    }
+
+    /// Deals `amount` of damage to a living ship, shield first, destroying it
+    /// once its health runs out. Returns whether this destroyed the ship.
+    fn apply_damage(&mut self, victim_id: u64, amount: f32) -> bool {
+        let now = self.tick;
+        let Some(victim) = self.players.get_mut(&victim_id) else {
+            return false;
+        };
+        if !victim.alive {
+            return false;
+        }
+        let mut amount = amount;
+        if let Some(rt) = self.runtime.get_mut(&victim_id) {
+            let absorbed = amount.min(rt.shield(now));
+            amount -= absorbed;
+            rt.shield_hp -= absorbed;
+            if absorbed > 0.0 && rt.shield_hp <= 0.0 {
+                rt.shield_until = None;
+            }
+            victim.shield = rt.shield(now);
+        }
+        victim.health = (victim.health - amount).max(0.0);
+        if victim.health > 0.0 {
+            return false;
+        }
+        self.apply_death(victim_id);
+        true
+    }
+
+    fn apply_death(&mut self, victim_id: u64) {
+        let now = self.tick;
+        let eliminated = self.single_life();
+
+        if let Some(victim) = self.players.get_mut(&victim_id) {
+            victim.alive = false;
+            // Death penalty: lose 15% of score
+            victim.score = ((victim.score as f32) * (1.0 - DEATH_PENALTY_PERCENT)) as u32;
+            victim.respawn_timer = (!eliminated).then_some(RESPAWN_DELAY_SECS);
+        }
+
+        if let Some(rt) = self.runtime.get_mut(&victim_id) {
+            if eliminated {
+                rt.respawn_at = None;
+                rt.eliminated_at = Some(now);
+            } else {
+                rt.respawn_at = Some(now + secs_to_ticks(RESPAWN_DELAY_SECS));
+            }
+            rt.combo = 0;
+            rt.kill_streak = 0;
+            // Clear power-up effects on death
+            rt.shield_until = None;
+            rt.shield_hp = 0.0;
+            rt.rapid_fire_until = None;
+            rt.triple_shot_until = None;
+            rt.speed_boost_until = None;
+        }
+
+        let Some(coop) = &mut self.coop else {
+            return;
+        };
+        coop.lives = coop.lives.saturating_sub(1);
+        if coop.lives == 0 {
+            // The run is lost: everyone is out until the next match
+            for player in self.players.values_mut() {
+                player.alive = false;
+                player.respawn_timer = None;
+            }
+            for rt in self.runtime.values_mut() {
+                rt.respawn_at = None;
+            }
+            self.end_match();
+        }
+    }
+
+    fn score_limit_reached(&self) -> bool {
+        let Some(limit) = self.rules.score_limit else {
+            return false;
+        };
+        match (&self.coop, self.mode.has_teams()) {
+            (Some(coop), _) => coop.score >= limit,
+            (None, true) => self.team_scores.values().any(|&s| s >= limit),
+            (None, false) => self.players.values().any(|p| p.score >= limit),
+        }
+    }
+
+    /// Whether deaths are final: battle royale players get one life per
+    /// match, though they respawn as usual during warmup.
+    fn single_life(&self) -> bool {
+        self.mode == GameMode::BattleRoyale && self.phase == MatchPhase::InProgress
+    }
+
+    /// A battle royale match is over once at most one of its players is
+    /// still flying. A player alone in the match plays on, even once others
+    /// join to watch.
+    fn last_ship_standing(&self) -> bool {
+        let contenders = self.players.keys().filter(|&&id| !self.is_spectating(id)).count();
+        self.mode == GameMode::BattleRoyale
+            && contenders >= 2
+            && self.players.values().filter(|p| p.alive).count() <= 1
+    }
+
+    fn is_spectating(&self, id: u64) -> bool {
+        self.runtime.get(&id).is_some_and(|rt| rt.spectating)
+    }
+
+    /// Moves on once the current phase has run out.
+    fn next_match_phase(&mut self) {
+        match self.phase {
+            MatchPhase::Warmup => {
+                self.reset_round();
+                self.phase = MatchPhase::InProgress;
+                self.phase_ends_at = self
+                    .rules
+                    .time_limit_secs
+                    .map(|secs| self.tick + secs_to_ticks(secs as f32));
+                if self.mode == GameMode::BattleRoyale {
+                    self.zone = Some(Zone {
+                        center: Vec2::new(
+                            self.rng.gen_range(0.0..WORLD_WIDTH),
+                            self.rng.gen_range(0.0..WORLD_HEIGHT),
+                        ),
+                        started_at: self.tick,
+                    });
+                }
+            }
+            MatchPhase::InProgress => self.end_match(),
+            MatchPhase::Ended => {
+                self.phase = MatchPhase::Intermission;
+                self.phase_ends_at = Some(self.tick + secs_to_ticks(INTERMISSION_SECS));
+            }
+            MatchPhase::Intermission => {
+                self.reset_round();
+                self.phase = MatchPhase::Warmup;
+                self.phase_ends_at = Some(self.tick + secs_to_ticks(WARMUP_SECS));
+            }
+        }
+    }
+
+    fn end_match(&mut self) {
+        if !self.phase.is_live() {
+            return;
+        }
+        self.phase = MatchPhase::Ended;
+        self.phase_ends_at = Some(self.tick + secs_to_ticks(MATCH_END_SECS));
+        self.bullets.clear();
+    }
+
+    /// Starts over at wave 1 with a fresh asteroid field and every player
+    /// back in with no score. Team scores, co-op lives and the battle royale
+    /// zone are reset too.
+    fn reset_round(&mut self) {
+        let now = self.tick;
+        self.zone = None;
+        if self.coop.is_some() {
+            self.coop = Some(CoopRun::new());
+        }
+        for score in self.team_scores.values_mut() {
+            *score = 0;
+        }
+        self.current_wave = 1;
+        self.wave_countdown = None;
+        self.boss = None;
+        self.ufos.clear();
+        self.next_ufo_at = now + secs_to_ticks(UFO_SPAWN_INTERVAL_SECS);
+        self.bullets.clear();
+        self.power_ups.clear();
+        self.asteroids = self.spawn_asteroids(ASTEROID_COUNT);
+        for (id, player) in self.players.iter_mut() {
+            player.pos = find_safe_spawn_position(&self.asteroids, &mut self.rng);
+            player.vel = Vec2::new(0.0, 0.0);
+            player.alive = true;
+            player.health = MAX_HEALTH;
+            player.shield = 0.0;
+            player.respawn_timer = None;
+            player.score = 0;
+            player.combo = 0;
+            player.kill_streak = 0;
+            if let Some(rt) = self.runtime.get_mut(id) {
+                *rt = PlayerRuntime {
+                    lag_ticks: rt.lag_ticks,
+                    invincible_until: Some(now + secs_to_ticks(SPAWN_INVINCIBILITY_SECS)),
+                    ..PlayerRuntime::new(now)
+                };
+            }
+        }
+    }
+
+    fn spawn_asteroids(&mut self, count: usize) -> Vec<AsteroidState> {
+        (0..count)
+            .map(|_| {
+                let kind = self.random_asteroid_kind();
+                // Cluster asteroids need room to shatter
+                let min_size = if kind == AsteroidKind::Cluster { 2 } else { 1 };
+                AsteroidState {
+                    id: self.next_entity_id(),
+                    pos: Vec2::new(
+                        self.rng.gen_range(0.0..WORLD_WIDTH),
+                        self.rng.gen_range(0.0..WORLD_HEIGHT),
+                    ),
+                    vel: Vec2::new(
+                        self.rng.gen_range(-2.5..2.5),
+                        self.rng.gen_range(-1.5..1.5),
+                    ),
+                    size: self.rng.gen_range(min_size..=3),
+                    damage: 0,
+                    kind,
+                }
+            })
+            .collect()
+    }
+
+    /// Puts this wave's boss somewhere clear of the ships.
+    fn spawn_boss(&mut self) {
+        let previous_bosses = self.current_wave / BOSS_WAVE_INTERVAL - 1;
+        let max_health = BOSS_HEALTH + previous_bosses as f32 * BOSS_HEALTH_PER_BOSS;
+        let mut pos = Vec2::new(WORLD_WIDTH / 2.0, WORLD_HEIGHT / 2.0);
+        for _ in 0..20 {
+            pos = Vec2::new(
+                self.rng.gen_range(0.0..WORLD_WIDTH),
+                self.rng.gen_range(0.0..WORLD_HEIGHT),
+            );
+            let clearance = BOSS_RADIUS + SAFE_SPAWN_RADIUS * 2.0;
+            if self
+                .players
+                .values()
+                .all(|p| distance_squared_wrapped(p.pos, pos) >= clearance * clearance)
+            {
+                break;
+            }
+        }
+        let id = self.next_entity_id();
+        self.boss = Some(Boss {
+            state: BossState {
+                id,
+                pos,
+                vel: Vec2::new(0.0, 0.0),
+                health: max_health,
+                max_health,
+            },
+            next_attack_at: self.tick + secs_to_ticks(BOSS_ATTACK_INTERVAL_SECS),
+            attacks: 0,
+        });
+    }
+
+    /// Steers the boss toward the nearest ship and launches its next attack
+    /// when one is due. It holds fire outside live phases.
+    fn update_boss(&mut self, live: bool, dt: f32) {
+        let now = self.tick;
+        let Some(boss) = &mut self.boss else {
+            return;
+        };
+        let target = self
+            .players
+            .values()
+            .filter(|p| p.alive)
+            .map(|p| p.pos)
+            .min_by(|a, b| {
+                distance_squared_wrapped(*a, boss.state.pos)
+                    .total_cmp(&distance_squared_wrapped(*b, boss.state.pos))
+            });
+        let aim = target.map(|t| {
+            shortest_delta(t.y, boss.state.pos.y, WORLD_HEIGHT)
+                .atan2(shortest_delta(t.x, boss.state.pos.x, WORLD_WIDTH))
+        });
+        boss.state.vel = match aim {
+            Some(angle) => Vec2::new(angle.cos(), angle.sin()).scale(BOSS_SPEED),
+            None => Vec2::new(0.0, 0.0),
+        };
+        boss.state.pos = wrap_position(boss.state.pos.add(boss.state.vel.scale(dt)));
+
+        if !live || now < boss.next_attack_at {
+            return;
+        }
+        let enraged = boss.state.health <= boss.state.max_health / 2.0;
+        let interval = if enraged {
+            BOSS_ATTACK_INTERVAL_SECS * BOSS_ENRAGED_INTERVAL_MULT
+        } else {
+            BOSS_ATTACK_INTERVAL_SECS
+        };
+        boss.next_attack_at = now + secs_to_ticks(interval);
+        let pattern = boss.attacks % 3;
+        boss.attacks += 1;
+        let (id, pos) = (boss.state.id, boss.state.pos);
+
+        match pattern {
+            0 => {
+                let offset = self.rng.gen_range(0.0..std::f32::consts::TAU);
+                for i in 0..BOSS_RING_BULLETS {
+                    let angle =
+                        offset + i as f32 * std::f32::consts::TAU / BOSS_RING_BULLETS as f32;
+                    self.fire_boss_bullet(id, pos, angle);
+                }
+            }
+            1 => {
+                let Some(aim) = aim else {
+                    return;
+                };
+                let spread = 0.15;
+                let first = aim - spread * (BOSS_VOLLEY_BULLETS - 1) as f32 / 2.0;
+                for i in 0..BOSS_VOLLEY_BULLETS {
+                    self.fire_boss_bullet(id, pos, first + i as f32 * spread);
+                }
+            }
+            _ => {
+                for _ in 0..BOSS_MINIONS {
+                    let angle = self.rng.gen_range(0.0..std::f32::consts::TAU);
+                    let dir = Vec2::new(angle.cos(), angle.sin());
+                    let speed = self.rng.gen_range(4.0..7.0);
+                    let id = self.next_entity_id();
+                    self.asteroids.push(AsteroidState {
+                        id,
+                        pos: wrap_position(pos.add(dir.scale(BOSS_RADIUS + 2.0))),
+                        vel: dir.scale(speed),
+                        size: 1,
+                        damage: 0,
+                        kind: AsteroidKind::Normal,
+                    });
+                }
+            }
+        }
+    }
+
+    fn fire_boss_bullet(&mut self, boss_id: u64, boss_pos: Vec2, angle: f32) {
+        let dir = Vec2::new(angle.cos(), angle.sin());
+        self.fire_enemy_bullet(
+            boss_id,
+            boss_pos.add(dir.scale(BOSS_RADIUS + 1.0)),
+            dir.scale(BOSS_BULLET_SPEED),
+        );
+    }
+
+    fn fire_enemy_bullet(&mut self, owner_id: u64, pos: Vec2, vel: Vec2) {
+        let id = self.next_entity_id();
+        self.bullets.push(BulletRuntime {
+            state: BulletState {
+                id,
+                owner_id,
+                enemy: true,
+                pos: wrap_position(pos),
+                vel,
+            },
+            expires_at: self.tick + secs_to_ticks(BULLET_TTL),
+        });
+    }
+
+    /// Spawns UFOs while a wave is on, steers each toward the nearest ship
+    /// and fires at it once it is in range. UFOs hold fire outside live
+    /// phases.
+    fn update_ufos(&mut self, live: bool, dt: f32) {
+        let now = self.tick;
+        if live && self.wave_countdown.is_none() && now >= self.next_ufo_at {
+            let interval = (UFO_SPAWN_INTERVAL_SECS
+                - self.current_wave as f32 * UFO_SPAWN_INTERVAL_STEP_SECS)
+                .max(UFO_MIN_SPAWN_INTERVAL_SECS);
+            self.next_ufo_at = now + secs_to_ticks(interval);
+            let max_ufos = (1 + self.current_wave as usize / 3).min(MAX_UFOS);
+            if self.ufos.len() < max_ufos {
+                self.spawn_ufo();
+            }
+        }
+
+        let mut shots: Vec<(u64, Vec2, f32)> = Vec::new();
+        for ufo in &mut self.ufos {
+            let target = self
+                .players
+                .values()
+                .filter(|p| p.alive)
+                .map(|p| (distance_squared_wrapped(p.pos, ufo.state.pos), p.pos))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            if let Some((dist_sq, target)) = target {
+                let dx = shortest_delta(target.x, ufo.state.pos.x, WORLD_WIDTH);
+                let dy = shortest_delta(target.y, ufo.state.pos.y, WORLD_HEIGHT);
+                let angle = dy.atan2(dx);
+                let dir = Vec2::new(angle.cos(), angle.sin());
+                ufo.state.vel = ufo.state.vel.add(dir.scale(UFO_ACCEL * dt));
+                let speed = (ufo.state.vel.x * ufo.state.vel.x + ufo.state.vel.y * ufo.state.vel.y)
+                    .sqrt();
+                if speed > UFO_MAX_SPEED {
+                    ufo.state.vel = ufo.state.vel.scale(UFO_MAX_SPEED / speed);
+                }
+                if live && now >= ufo.next_fire_at && dist_sq < UFO_FIRE_RANGE * UFO_FIRE_RANGE {
+                    ufo.next_fire_at = now + secs_to_ticks(UFO_FIRE_INTERVAL_SECS);
+                    shots.push((ufo.state.id, ufo.state.pos, angle));
+                }
+            }
+            ufo.state.pos = wrap_position(ufo.state.pos.add(ufo.state.vel.scale(dt)));
+        }
+
+        for (id, pos, angle) in shots {
+            let angle = angle + self.rng.gen_range(-UFO_AIM_ERROR..UFO_AIM_ERROR);
+            let dir = Vec2::new(angle.cos(), angle.sin());
+            self.fire_enemy_bullet(
+                id,
+                pos.add(dir.scale(UFO_RADIUS + 1.0)),
+                dir.scale(UFO_BULLET_SPEED),
+            );
+        }
+    }
+
+    /// Puts a UFO somewhere out of sight of the ships.
+    fn spawn_ufo(&mut self) {
+        let mut pos = Vec2::new(0.0, 0.0);
+        for _ in 0..20 {
+            pos = Vec2::new(
+                self.rng.gen_range(0.0..WORLD_WIDTH),
+                self.rng.gen_range(0.0..WORLD_HEIGHT),
+            );
+            if self.players.values().all(|p| {
+                distance_squared_wrapped(p.pos, pos) >= UFO_SPAWN_CLEARANCE * UFO_SPAWN_CLEARANCE
+            }) {
+                break;
+            }
+        }
+        let id = self.next_entity_id();
+        self.ufos.push(Ufo {
+            state: UfoState {
+                id,
+                pos,
+                vel: Vec2::new(0.0, 0.0),
+                health: UFO_HEALTH,
+            },
+            next_fire_at: self.tick + secs_to_ticks(UFO_FIRE_INTERVAL_SECS),
+        });
+    }
+
+    /// Adds `points` to a player's score, and to their team's or the co-op
+    /// run's.
+    fn award_points(&mut self, player_id: u64, points: u32) {
+        let Some(player) = self.players.get_mut(&player_id) else {
+            return;
+        };
+        player.score += points;
+        if let Some(team) = player.team {
+            *self.team_scores.entry(team).or_default() += points;
+        }
+        if let Some(coop) = &mut self.coop {
+            coop.score += points;
+        }
+    }
+
+    /// Each special kind is equally likely, and more likely the later the
+    /// wave.
+    fn random_asteroid_kind(&mut self) -> AsteroidKind {
+        let chance = (SPECIAL_ASTEROID_BASE_CHANCE
+            + (self.current_wave - 1) as f32 * SPECIAL_ASTEROID_CHANCE_PER_WAVE)
+            .min(SPECIAL_ASTEROID_MAX_CHANCE);
+        let roll = self.rng.gen::<f32>();
+        if roll < chance {
+            AsteroidKind::Explosive
+        } else if roll < chance * 2.0 {
+            AsteroidKind::Magnetic
+        } else if roll < chance * 3.0 {
+            AsteroidKind::Cluster
+        } else {
+            AsteroidKind::Normal
+        }
+    }
+
+    fn next_entity_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn u64(&mut self, v: u64) {
+        for byte in v.to_le_bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn f32(&mut self, v: f32) {
+        self.u64(v.to_bits() as u64);
+    }
+
+    fn vec2(&mut self, v: Vec2) {
+        self.f32(v.x);
+        self.f32(v.y);
+    }
+
+    fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+fn power_up_kind(rng: &mut impl Rng) -> PowerUpKind {
+    match rng.gen_range(0..4) {
+        0 => PowerUpKind::Shield,
+        1 => PowerUpKind::RapidFire,
+        2 => PowerUpKind::TripleShot,
+        _ => PowerUpKind::SpeedBoost,
+    }
+}
+
+fn find_safe_spawn_position(asteroids: &[AsteroidState], rng: &mut impl Rng) -> Vec2 {
+    for _ in 0..50 {
+        let pos = Vec2::new(
+            rng.gen_range(0.0..WORLD_WIDTH),
+            rng.gen_range(0.0..WORLD_HEIGHT),
+        );
+        let mut safe = true;
+        for ast in asteroids {
+            let dist_sq = distance_squared_wrapped(pos, ast.pos);
+            let min_dist = SAFE_SPAWN_RADIUS + asteroid_radius(ast.size);
+            if dist_sq < min_dist * min_dist {
+                safe = false;
+                break;
+            }
+        }
+        if safe {
+            return pos;
+        }
+    }
+    // Fallback: just pick random position
+    Vec2::new(
+        rng.gen_range(0.0..WORLD_WIDTH),
+        rng.gen_range(0.0..WORLD_HEIGHT),
+    )
+}
+
+fn spawn_player(
+    id: u64,
+    name: String,
+    asteroids: &[AsteroidState],
+    rng: &mut impl Rng,
+) -> PlayerState {
+    let pos = find_safe_spawn_position(asteroids, rng);
+    PlayerState {
+        id,
+        name,
+        pos,
+        vel: Vec2::new(0.0, 0.0),
+        angle: rng.gen_range(0.0..std::f32::consts::TAU),
+        alive: true,
+        score: 0,
+        combo: 0,
+        kill_streak: 0,
+        respawn_timer: None,
+        effects: PlayerEffects::default(),
+        last_input_seq: 0,
+        team: None,
+        health: MAX_HEALTH,
+        shield: 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A world in the middle of a match with one ship far from everything and
+    /// no asteroids.
+    fn empty_match() -> World {
+        let mut world = World::new(7);
+        world.asteroids.clear();
+        world.phase = MatchPhase::InProgress;
+        world.phase_ends_at = None;
+        world.add_player(1, "shooter".to_string());
+        world.players.get_mut(&1).unwrap().pos = Vec2::new(10.0, 10.0);
+        world
+    }
+
+    /// Adds an asteroid of `size` that breaks on its next hit.
+    fn add_asteroid(world: &mut World, pos: Vec2, size: u8) -> u64 {
+        let id = world.next_entity_id();
+        world.asteroids.push(AsteroidState {
+            id,
+            pos,
+            vel: Vec2::new(0.0, 0.0),
+            size,
+            damage: asteroid_hit_points(size) - 1,
+            kind: AsteroidKind::Normal,
+        });
+        id
+    }
+
+    fn add_bullet(world: &mut World, owner_id: u64, pos: Vec2) {
+        let id = world.next_entity_id();
+        world.bullets.push(BulletRuntime {
+            state: BulletState {
+                id,
+                owner_id,
+                enemy: false,
+                pos,
+                vel: Vec2::new(0.0, 0.0),
+            },
+            expires_at: world.tick + secs_to_ticks(BULLET_TTL),
+        });
+    }
+
+    fn step(world: &mut World) {
+        world.step(&HashMap::new(), TICK_DT);
+    }
+
+    /// Scripted input for player `id` at `tick`: turning, thrusting and
+    /// firing in a pattern that differs between players.
+    fn scripted_input(id: u64, tick: u64) -> PlayerInput {
+        PlayerInput {
+            thrust: (tick / 7 + id) % 3 != 1,
+            target_angle: Some(((tick / 20 + id) % 8) as f32 * std::f32::consts::FRAC_PI_4),
+            fire: (tick + id) % 4 == 1,
+            seq: tick as u32,
+        }
+    }
+
+    fn run_scripted(seed: u64, ticks: u64) -> World {
+        let mut world = World::with_mode(seed, GameMode::Coop);
+        for id in 1..=3 {
+            world.add_player(id, format!("player {id}"));
+        }
+        for tick in 0..ticks {
+            let inputs = (1..=3).map(|id| (id, scripted_input(id, tick))).collect();
+            world.step(&inputs, TICK_DT);
+        }
+        world
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_the_same_world() {
+        let ticks = secs_to_ticks(WARMUP_SECS + 30.0);
+        let a = run_scripted(42, ticks);
+        let b = run_scripted(42, ticks);
+        assert_eq!(a.checksum(), b.checksum());
+        assert_eq!(a.snapshot(), b.snapshot());
+        assert_ne!(a.checksum(), run_scripted(43, ticks).checksum());
+    }
+
+    #[test]
+    fn breaking_an_asteroid_splits_it_in_two() {
+        let mut world = empty_match();
+        let pos = Vec2::new(120.0, 40.0);
+        let id = add_asteroid(&mut world, pos, 3);
+        add_bullet(&mut world, 1, pos);
+        step(&mut world);
+
+        assert!(world.asteroids.iter().all(|a| a.id != id));
+        assert_eq!(world.asteroids.len(), 2);
+        assert!(world.asteroids.iter().all(|a| a.size == 2 && a.damage == 0));
+        assert_eq!(world.bullets.len(), 0);
+    }
+
+    #[test]
+    fn hitting_an_asteroid_without_breaking_it_scores_the_hit() {
+        let mut world = empty_match();
+        let pos = Vec2::new(120.0, 40.0);
+        add_asteroid(&mut world, pos, 3);
+        world.asteroids[0].damage = 0;
+        add_bullet(&mut world, 1, pos);
+        step(&mut world);
+
+        assert_eq!(world.asteroids.len(), 1);
+        assert_eq!(world.asteroids[0].damage, 1);
+        assert_eq!(world.player(1).unwrap().score, ASTEROID_HIT_POINTS);
+    }
+
+    #[test]
+    fn large_asteroids_take_several_bullets_to_break() {
+        let mut world = empty_match();
+        let pos = Vec2::new(120.0, 40.0);
+        let id = add_asteroid(&mut world, pos, 3);
+        world.asteroids[0].damage = 0;
+        for hit in 1..asteroid_hit_points(3) {
+            add_bullet(&mut world, 1, pos);
+            step(&mut world);
+            assert_eq!(world.asteroids.len(), 1);
+            assert_eq!(world.asteroids[0].damage, hit);
+        }
+
+        add_bullet(&mut world, 1, pos);
+        step(&mut world);
+        assert!(world.asteroids.iter().all(|a| a.id != id));
+    }
+
+    #[test]
+    fn smallest_asteroids_are_destroyed_outright() {
+        let mut world = empty_match();
+        let pos = Vec2::new(120.0, 40.0);
+        add_asteroid(&mut world, pos, 1);
+        add_bullet(&mut world, 1, pos);
+        step(&mut world);
+
+        assert!(world.asteroids.is_empty());
+        assert_eq!(world.player(1).unwrap().score, ASTEROID_HIT_POINTS + 100);
+    }
+
+    #[test]
+    fn explosive_asteroids_take_out_their_neighbours_and_hurt_ships() {
+        let mut world = empty_match();
+        let pos = Vec2::new(120.0, 40.0);
+        add_asteroid(&mut world, pos, 1);
+        world.asteroids[0].kind = AsteroidKind::Explosive;
+        add_asteroid(&mut world, Vec2::new(125.0, 40.0), 3);
+        let distant = add_asteroid(&mut world, Vec2::new(160.0, 40.0), 3);
+        world.players.get_mut(&1).unwrap().pos = Vec2::new(120.0, 46.0);
+        world.runtime.get_mut(&1).unwrap().invincible_until = None;
+        add_bullet(&mut world, 1, pos);
+        step(&mut world);
+
+        let left: Vec<u64> = world.asteroids.iter().map(|a| a.id).collect();
+        assert_eq!(left, [distant]);
+        assert_eq!(world.player(1).unwrap().health, MAX_HEALTH - EXPLOSION_DAMAGE);
+    }
+
+    #[test]
+    fn quick_kills_build_a_combo_that_times_out() {
+        let mut world = empty_match();
+        let targets = [
+            Vec2::new(60.0, 40.0),
+            Vec2::new(120.0, 40.0),
+            Vec2::new(180.0, 40.0),
+        ];
+        for pos in targets {
+            add_asteroid(&mut world, pos, 1);
+        }
+
+        add_bullet(&mut world, 1, targets[0]);
+        step(&mut world);
+        assert_eq!(world.player(1).unwrap().combo, 1);
+        add_bullet(&mut world, 1, targets[1]);
+        step(&mut world);
+        assert_eq!(world.player(1).unwrap().combo, 2);
+        let two_kills = ASTEROID_HIT_POINTS + 100 + ASTEROID_HIT_POINTS + 100 * 2;
+        assert_eq!(world.player(1).unwrap().score, two_kills);
+
+        for _ in 0..secs_to_ticks(COMBO_TIMEOUT_SECS) {
+            step(&mut world);
+        }
+        add_bullet(&mut world, 1, targets[2]);
+        step(&mut world);
+        assert_eq!(world.player(1).unwrap().combo, 1);
+        assert_eq!(
+            world.player(1).unwrap().score,
+            two_kills + ASTEROID_HIT_POINTS + 100
+        );
+    }
+
+    #[test]
+    fn coop_run_scores_with_its_players() {
+        let mut world = World::with_mode(7, GameMode::Coop);
+        world.asteroids.clear();
+        world.phase = MatchPhase::InProgress;
+        world.add_player(1, "shooter".to_string());
+        let pos = Vec2::new(120.0, 40.0);
+        add_asteroid(&mut world, pos, 1);
+        add_bullet(&mut world, 1, pos);
+        step(&mut world);
+
+        assert_eq!(
+            world.coop_info().unwrap().score,
+            world.player(1).unwrap().score
+        );
+    }
+
+    #[test]
+    fn boss_bullets_are_told_apart_from_a_player_with_the_same_id() {
+        let mut world = empty_match();
+        world.runtime.get_mut(&1).unwrap().invincible_until = None;
+        // Both bullets are fired from just left of their target, heading right
+        let ship = world.player(1).unwrap().pos;
+        world.fire_boss_bullet(1, Vec2::new(ship.x - BOSS_RADIUS - 1.0, ship.y), 0.0);
+        let rock = Vec2::new(120.0, 40.0);
+        let asteroid = add_asteroid(&mut world, rock, 3);
+        world.fire_boss_bullet(1, Vec2::new(rock.x - BOSS_RADIUS - 1.0, rock.y), 0.0);
+        step(&mut world);
+
+        let player = world.player(1).unwrap();
+        assert_eq!(player.health, MAX_HEALTH - BULLET_DAMAGE);
+        assert_eq!(player.score, 0);
+        assert!(world.asteroids.iter().any(|a| a.id == asteroid));
+    }
+
+    #[test]
+    fn ufo_bullets_are_told_apart_from_a_player_with_the_same_id() {
+        let mut world = empty_match();
+        world.runtime.get_mut(&1).unwrap().invincible_until = None;
+        let ship = world.player(1).unwrap().pos;
+        world.fire_enemy_bullet(1, ship, Vec2::new(0.0, 0.0));
+        let pos = Vec2::new(120.0, 40.0);
+        let id = world.next_entity_id();
+        world.ufos.push(Ufo {
+            state: UfoState {
+                id,
+                pos,
+                vel: Vec2::new(0.0, 0.0),
+                health: UFO_HEALTH,
+            },
+            next_fire_at: u64::MAX,
+        });
+        world.fire_enemy_bullet(1, pos, Vec2::new(0.0, 0.0));
+        step(&mut world);
+
+        let player = world.player(1).unwrap();
+        assert_eq!(player.health, MAX_HEALTH - BULLET_DAMAGE);
+        assert_eq!(player.score, 0);
+        assert_eq!(world.ufos().next().unwrap().health, UFO_HEALTH);
+        assert!(world.bullets().all(|b| b.enemy && b.owner_id == 1));
+    }
+
+    #[test]
+    fn asteroid_damage_grows_with_size_and_shields_absorb_it() {
+        let mut world = empty_match();
+        world.add_player(2, "bigger rock".to_string());
+        world.add_player(3, "shielded".to_string());
+        let spots = [
+            (1, Vec2::new(20.0, 20.0), 1),
+            (2, Vec2::new(60.0, 20.0), 3),
+            (3, Vec2::new(100.0, 20.0), 3),
+        ];
+        for (id, pos, size) in spots {
+            world.players.get_mut(&id).unwrap().pos = pos;
+            world.runtime.get_mut(&id).unwrap().invincible_until = None;
+            add_asteroid(&mut world, pos, size);
+        }
+        let rt = world.runtime.get_mut(&3).unwrap();
+        rt.shield_until = Some(u64::MAX);
+        rt.shield_hp = SHIELD_STRENGTH;
+        step(&mut world);
+
+        let small_hit = asteroid_radius(1) * ASTEROID_BASE_DAMAGE;
+        let large_hit = asteroid_radius(3) * ASTEROID_BASE_DAMAGE;
+        assert!(small_hit < large_hit);
+        assert_eq!(world.player(1).unwrap().health, MAX_HEALTH - small_hit);
+        assert_eq!(world.player(2).unwrap().health, MAX_HEALTH - large_hit);
+        let shielded = world.player(3).unwrap();
+        assert_eq!(shielded.health, MAX_HEALTH);
+        assert_eq!(shielded.shield, SHIELD_STRENGTH - large_hit);
+    }
+
+    /// Steps `world` through `secs` of play.
+    fn run_for(world: &mut World, secs: f32) {
+        for _ in 0..secs_to_ticks(secs) {
+            step(world);
+        }
+    }
+
+    #[test]
+    fn a_timed_match_runs_through_every_phase_and_ranks_by_score() {
+        let rules = MatchRules {
+            time_limit_secs: Some(2),
+            score_limit: None,
+        };
+        let mut world = World::with_rules(7, GameMode::FreeForAll, rules);
+        world.add_player(1, "first".to_string());
+        world.add_player(2, "second".to_string());
+        assert_eq!(world.match_phase(), MatchPhase::Warmup);
+
+        run_for(&mut world, WARMUP_SECS);
+        assert_eq!(world.match_phase(), MatchPhase::InProgress);
+        world.asteroids.clear();
+        world.players.get_mut(&1).unwrap().score = 200;
+        world.players.get_mut(&2).unwrap().score = 500;
+
+        run_for(&mut world, 1.0);
+        assert_eq!(world.match_phase(), MatchPhase::InProgress);
+        run_for(&mut world, 1.0);
+        assert_eq!(world.match_phase(), MatchPhase::Ended);
+        let results: Vec<(u64, u32)> = world.standings().iter().map(|s| (s.id, s.score)).collect();
+        assert_eq!(results, [(2, 500), (1, 200)]);
+
+        run_for(&mut world, MATCH_END_SECS);
+        assert_eq!(world.match_phase(), MatchPhase::Intermission);
+        run_for(&mut world, INTERMISSION_SECS);
+        assert_eq!(world.match_phase(), MatchPhase::Warmup);
+        assert!(world.players().all(|p| p.score == 0));
+        assert_eq!(world.current_wave(), 1);
+    }
+
+    /// A battle royale match under way between `ids`, in a world without
+    /// asteroids.
+    fn battle_royale(ids: &[u64]) -> World {
+        let mut world = World::with_mode(7, GameMode::BattleRoyale);
+        world.asteroids.clear();
+        for &id in ids {
+            world.add_player(id, format!("player {id}"));
+        }
+        world.phase = MatchPhase::InProgress;
+        world.phase_ends_at = None;
+        world
+    }
+
+    #[test]
+    fn a_late_joiner_does_not_end_a_solo_battle_royale() {
+        let mut world = battle_royale(&[1]);
+        world.add_player(2, "late".to_string());
+        step(&mut world);
+
+        assert!(!world.player(2).unwrap().alive);
+        assert_eq!(world.match_phase(), MatchPhase::InProgress);
+    }
+
+    #[test]
+    fn late_joiners_are_left_out_of_battle_royale_placings() {
+        let mut world = battle_royale(&[1, 2]);
+        world.add_player(3, "late".to_string());
+        step(&mut world);
+        assert_eq!(world.match_phase(), MatchPhase::InProgress);
+
+        world.apply_death(2);
+        step(&mut world);
+        assert_eq!(world.match_phase(), MatchPhase::Ended);
+        let placings: Vec<u64> = world.standings().iter().map(|s| s.id).collect();
+        assert_eq!(placings, [1, 2]);
+    }
+
+    #[test]
+    fn next_wave_starts_after_the_countdown() {
+        let mut world = empty_match();
+        step(&mut world);
+        assert_eq!(world.current_wave(), 1);
+        assert!(world.wave_info().countdown.is_some());
+
+        for _ in 0..secs_to_ticks(WAVE_COUNTDOWN_SECS) {
+            step(&mut world);
+        }
+        assert_eq!(world.current_wave(), 2);
+        assert!(world.wave_info().countdown.is_none());
+        assert_eq!(world.asteroids.len(), ASTEROID_COUNT + ASTEROIDS_PER_WAVE);
+    }
+
+    #[test]
+    fn every_fifth_wave_brings_a_boss_and_half_the_asteroids() {
+        let mut world = empty_match();
+        world.current_wave = BOSS_WAVE_INTERVAL - 1;
+        for _ in 0..=secs_to_ticks(WAVE_COUNTDOWN_SECS) {
+            step(&mut world);
+        }
+        assert_eq!(world.current_wave(), BOSS_WAVE_INTERVAL);
+        assert!(world.boss().is_some());
+        let full = ASTEROID_COUNT + (BOSS_WAVE_INTERVAL as usize - 1) * ASTEROIDS_PER_WAVE;
+        assert_eq!(world.asteroids.len(), full / 2);
+    }
+
+    #[test]
+    fn the_first_boss_arrives_on_wave_five_and_holds_the_wave() {
+        let mut world = empty_match();
+        for wave in 1..5 {
+            assert_eq!(world.current_wave(), wave);
+            assert!(world.boss().is_none());
+            world.asteroids.clear();
+            for _ in 0..=secs_to_ticks(WAVE_COUNTDOWN_SECS) {
+                step(&mut world);
+            }
+        }
+        assert_eq!(world.current_wave(), 5);
+        assert!(world.boss().is_some());
+
+        world.asteroids.clear();
+        for _ in 0..=secs_to_ticks(WAVE_COUNTDOWN_SECS) {
+            step(&mut world);
+        }
+        assert_eq!(world.current_wave(), 5);
+    }
+}
@@ -0,0 +1,535 @@
+//! World snapshots and the deltas between them.
+//!
+//! The server keeps a short history of [`Snapshot`]s. Once a client has
+//! acknowledged one of them, the server sends a [`SnapshotDelta`] against it
+//! instead of the full world: entities that appeared or changed in any way
+//! other than moving are sent whole, moving entities only carry the motion
+//! fields that changed, and removed entities are listed by id. Players' power-up
+//! timers, health and shield change far more often than the rest of their
+//! record, so they are sent on their own.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AsteroidState, BossState, BulletState, CoopInfo, MatchInfo, PlayerEffects, PlayerState,
+    PowerUpState, SafeZone, TeamScore, UfoState, Vec2, WaveInfo,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct Snapshot {
+    /// Simulation tick this state was taken at.
+    #[serde(default)]
+    pub tick: u64,
+    pub players: Vec<PlayerState>,
+    pub asteroids: Vec<AsteroidState>,
+    pub bullets: Vec<BulletState>,
+    #[serde(default)]
+    pub power_ups: Vec<PowerUpState>,
+    #[serde(default)]
+    pub ufos: Vec<UfoState>,
+    #[serde(default)]
+    pub wave: Option<WaveInfo>,
+    /// Empty outside team modes.
+    #[serde(default)]
+    pub teams: Vec<TeamScore>,
+    #[serde(default)]
+    pub coop: Option<CoopInfo>,
+    #[serde(default)]
+    pub match_info: Option<MatchInfo>,
+    #[serde(default)]
+    pub zone: Option<SafeZone>,
+    #[serde(default)]
+    pub boss: Option<BossState>,
+}
+
+/// New position of an entity, plus its velocity and heading when those changed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Motion {
+    pub id: u64,
+    pub pos: Vec2,
+    #[serde(default)]
+    pub vel: Option<Vec2>,
+    #[serde(default, with = "crate::codec::opt_angle")]
+    pub angle: Option<f32>,
+    /// Last input applied to a player's ship, when that changed.
+    #[serde(default)]
+    pub last_input_seq: Option<u32>,
+}
+
+/// A player's power-up and invincibility timers, when they changed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EffectsChange {
+    pub id: u64,
+    pub effects: PlayerEffects,
+}
+
+/// A player's health and shield, when either changed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HealthChange {
+    pub id: u64,
+    pub health: f32,
+    pub shield: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EntityDelta<T> {
+    /// Entities that are new, or changed in more than their motion.
+    pub upserted: Vec<T>,
+    pub moved: Vec<Motion>,
+    pub removed: Vec<u64>,
+}
+
+impl<T> Default for EntityDelta<T> {
+    fn default() -> Self {
+        Self {
+            upserted: Vec::new(),
+            moved: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+}
+
+/// Difference between the snapshot at `base_tick` and the one at `tick`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotDelta {
+    pub tick: u64,
+    pub base_tick: u64,
+    pub players: EntityDelta<PlayerState>,
+    /// For players not in `players.upserted`.
+    #[serde(default)]
+    pub player_effects: Vec<EffectsChange>,
+    /// For players not in `players.upserted`.
+    #[serde(default)]
+    pub player_health: Vec<HealthChange>,
+    pub asteroids: EntityDelta<AsteroidState>,
+    pub bullets: EntityDelta<BulletState>,
+    pub power_ups: EntityDelta<PowerUpState>,
+    #[serde(default)]
+    pub ufos: EntityDelta<UfoState>,
+    pub wave: Option<WaveInfo>,
+    /// Sent whole; there are only ever a couple of teams.
+    #[serde(default)]
+    pub teams: Vec<TeamScore>,
+    #[serde(default)]
+    pub coop: Option<CoopInfo>,
+    #[serde(default)]
+    pub match_info: Option<MatchInfo>,
+    #[serde(default)]
+    pub zone: Option<SafeZone>,
+    #[serde(default)]
+    pub boss: Option<BossState>,
+}
+
+/// Something in the world that can be diffed by id.
+pub trait Entity: Clone + PartialEq {
+    fn id(&self) -> u64;
+    fn pos(&self) -> Vec2;
+    fn set_pos(&mut self, pos: Vec2);
+
+    fn vel(&self) -> Option<Vec2> {
+        None
+    }
+
+    fn set_vel(&mut self, _vel: Vec2) {}
+
+    fn angle(&self) -> Option<f32> {
+        None
+    }
+
+    fn set_angle(&mut self, _angle: f32) {}
+
+    fn last_input_seq(&self) -> Option<u32> {
+        None
+    }
+
+    fn set_last_input_seq(&mut self, _seq: u32) {}
+
+    fn apply_motion(&mut self, motion: &Motion) {
+        self.set_pos(motion.pos);
+        if let Some(vel) = motion.vel {
+            self.set_vel(vel);
+        }
+        if let Some(angle) = motion.angle {
+            self.set_angle(angle);
+        }
+        if let Some(seq) = motion.last_input_seq {
+            self.set_last_input_seq(seq);
+        }
+    }
+}
+
+impl Entity for PlayerState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn pos(&self) -> Vec2 {
+        self.pos
+    }
+
+    fn set_pos(&mut self, pos: Vec2) {
+        self.pos = pos;
+    }
+
+    fn vel(&self) -> Option<Vec2> {
+        Some(self.vel)
+    }
+
+    fn set_vel(&mut self, vel: Vec2) {
+        self.vel = vel;
+    }
+
+    fn angle(&self) -> Option<f32> {
+        Some(self.angle)
+    }
+
+    fn set_angle(&mut self, angle: f32) {
+        self.angle = angle;
+    }
+
+    fn last_input_seq(&self) -> Option<u32> {
+        Some(self.last_input_seq)
+    }
+
+    fn set_last_input_seq(&mut self, seq: u32) {
+        self.last_input_seq = seq;
+    }
+}
+
+impl Entity for AsteroidState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn pos(&self) -> Vec2 {
+        self.pos
+    }
+
+    fn set_pos(&mut self, pos: Vec2) {
+        self.pos = pos;
+    }
+
+    fn vel(&self) -> Option<Vec2> {
+        Some(self.vel)
+    }
+
+    fn set_vel(&mut self, vel: Vec2) {
+        self.vel = vel;
+    }
+}
+
+impl Entity for BulletState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn pos(&self) -> Vec2 {
+        self.pos
+    }
+
+    fn set_pos(&mut self, pos: Vec2) {
+        self.pos = pos;
+    }
+
+    fn vel(&self) -> Option<Vec2> {
+        Some(self.vel)
+    }
+
+    fn set_vel(&mut self, vel: Vec2) {
+        self.vel = vel;
+    }
+}
+
+impl Entity for UfoState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn pos(&self) -> Vec2 {
+        self.pos
+    }
+
+    fn set_pos(&mut self, pos: Vec2) {
+        self.pos = pos;
+    }
+
+    fn vel(&self) -> Option<Vec2> {
+        Some(self.vel)
+    }
+
+    fn set_vel(&mut self, vel: Vec2) {
+        self.vel = vel;
+    }
+}
+
+impl Entity for PowerUpState {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn pos(&self) -> Vec2 {
+        self.pos
+    }
+
+    fn set_pos(&mut self, pos: Vec2) {
+        self.pos = pos;
+    }
+}
+
+impl Snapshot {
+    /// Computes the delta that turns `self` into `newer`.
+    pub fn diff(&self, newer: &Snapshot) -> SnapshotDelta {
+        let (players, player_effects, player_health) = diff_players(&self.players, &newer.players);
+        SnapshotDelta {
+            tick: newer.tick,
+            base_tick: self.tick,
+            players,
+            player_effects,
+            player_health,
+            asteroids: diff_entities(&self.asteroids, &newer.asteroids),
+            bullets: diff_entities(&self.bullets, &newer.bullets),
+            power_ups: diff_entities(&self.power_ups, &newer.power_ups),
+            ufos: diff_entities(&self.ufos, &newer.ufos),
+            wave: newer.wave.clone(),
+            teams: newer.teams.clone(),
+            coop: newer.coop.clone(),
+            match_info: newer.match_info.clone(),
+            zone: newer.zone.clone(),
+            boss: newer.boss.clone(),
+        }
+    }
+
+    /// Rebuilds the newer snapshot from `self` and a delta against it, or
+    /// returns `None` if the delta was computed against a different tick.
+    pub fn apply(&self, delta: &SnapshotDelta) -> Option<Snapshot> {
+        if delta.base_tick != self.tick {
+            return None;
+        }
+        let mut players = apply_entities(&self.players, &delta.players);
+        let effects: HashMap<u64, &PlayerEffects> = delta
+            .player_effects
+            .iter()
+            .map(|c| (c.id, &c.effects))
+            .collect();
+        let health: HashMap<u64, &HealthChange> =
+            delta.player_health.iter().map(|c| (c.id, c)).collect();
+        for player in &mut players {
+            if let Some(&effects) = effects.get(&player.id) {
+                player.effects = effects.clone();
+            }
+            if let Some(change) = health.get(&player.id) {
+                player.health = change.health;
+                player.shield = change.shield;
+            }
+        }
+        Some(Snapshot {
+            tick: delta.tick,
+            players,
+            asteroids: apply_entities(&self.asteroids, &delta.asteroids),
+            bullets: apply_entities(&self.bullets, &delta.bullets),
+            power_ups: apply_entities(&self.power_ups, &delta.power_ups),
+            ufos: apply_entities(&self.ufos, &delta.ufos),
+            wave: delta.wave.clone(),
+            teams: delta.teams.clone(),
+            coop: delta.coop.clone(),
+            match_info: delta.match_info.clone(),
+            zone: delta.zone.clone(),
+            boss: delta.boss.clone(),
+        })
+    }
+}
+
+fn diff_entities<T: Entity>(old: &[T], new: &[T]) -> EntityDelta<T> {
+    let old_by_id: HashMap<u64, &T> = old.iter().map(|e| (e.id(), e)).collect();
+    let mut delta = EntityDelta::default();
+
+    for entity in new {
+        let Some(&before) = old_by_id.get(&entity.id()) else {
+            delta.upserted.push(entity.clone());
+            continue;
+        };
+        if before == entity {
+            continue;
+        }
+
+        let motion = Motion {
+            id: entity.id(),
+            pos: entity.pos(),
+            vel: entity.vel().filter(|v| Some(*v) != before.vel()),
+            angle: entity.angle().filter(|a| Some(*a) != before.angle()),
+            last_input_seq: entity
+                .last_input_seq()
+                .filter(|s| Some(*s) != before.last_input_seq()),
+        };
+        let mut moved = before.clone();
+        moved.apply_motion(&motion);
+        if moved == *entity {
+            delta.moved.push(motion);
+        } else {
+            delta.upserted.push(entity.clone());
+        }
+    }
+
+    let new_ids: HashSet<u64> = new.iter().map(|e| e.id()).collect();
+    delta.removed = old
+        .iter()
+        .map(|e| e.id())
+        .filter(|id| !new_ids.contains(id))
+        .collect();
+    delta
+}
+
+/// Like [`diff_entities`], with timers, health and shield of players that
+/// were already there split off into changes of their own.
+fn diff_players(
+    old: &[PlayerState],
+    new: &[PlayerState],
+) -> (
+    EntityDelta<PlayerState>,
+    Vec<EffectsChange>,
+    Vec<HealthChange>,
+) {
+    let new_by_id: HashMap<u64, &PlayerState> = new.iter().map(|p| (p.id, p)).collect();
+    let mut effects = Vec::new();
+    let mut health = Vec::new();
+    // Diff against the old records with the split-off fields already brought
+    // up to date, so that they alone never cause an upsert
+    let old: Vec<PlayerState> = old
+        .iter()
+        .map(|before| {
+            let mut before = before.clone();
+            let Some(&after) = new_by_id.get(&before.id) else {
+                return before;
+            };
+            if before.effects != after.effects {
+                before.effects = after.effects.clone();
+                effects.push(EffectsChange {
+                    id: after.id,
+                    effects: after.effects.clone(),
+                });
+            }
+            if before.health != after.health || before.shield != after.shield {
+                before.health = after.health;
+                before.shield = after.shield;
+                health.push(HealthChange {
+                    id: after.id,
+                    health: after.health,
+                    shield: after.shield,
+                });
+            }
+            before
+        })
+        .collect();
+
+    let delta = diff_entities(&old, new);
+    let upserted: HashSet<u64> = delta.upserted.iter().map(|p| p.id).collect();
+    effects.retain(|c| !upserted.contains(&c.id));
+    health.retain(|c| !upserted.contains(&c.id));
+    (delta, effects, health)
+}
+
+fn apply_entities<T: Entity>(base: &[T], delta: &EntityDelta<T>) -> Vec<T> {
+    let removed: HashSet<u64> = delta.removed.iter().copied().collect();
+    let mut upserted: HashMap<u64, &T> = delta.upserted.iter().map(|e| (e.id(), e)).collect();
+    let moved: HashMap<u64, &Motion> = delta.moved.iter().map(|m| (m.id, m)).collect();
+
+    let mut entities = Vec::with_capacity(base.len() + upserted.len());
+    for entity in base {
+        let id = entity.id();
+        if removed.contains(&id) {
+            continue;
+        }
+        if let Some(replacement) = upserted.remove(&id) {
+            entities.push(replacement.clone());
+        } else if let Some(motion) = moved.get(&id) {
+            let mut entity = entity.clone();
+            entity.apply_motion(motion);
+            entities.push(entity);
+        } else {
+            entities.push(entity.clone());
+        }
+    }
+    // Whatever is left was spawned since the base snapshot
+    entities.extend(
+        delta
+            .upserted
+            .iter()
+            .filter(|e| upserted.contains_key(&e.id()))
+            .cloned(),
+    );
+    entities
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::sim::{World, TICK_DT};
+    use crate::{GameMode, PlayerInput};
+
+    /// Snapshots of a busy co-op world, one per tick.
+    fn recorded_snapshots(ticks: u64) -> Vec<Snapshot> {
+        let mut world = World::with_mode(3, GameMode::Coop);
+        for id in 1..=3 {
+            world.add_player(id, format!("player {id}"));
+        }
+        let mut snapshots = vec![world.snapshot()];
+        for tick in 0..ticks {
+            let inputs: HashMap<u64, PlayerInput> = (1..=3)
+                .map(|id| {
+                    let input = PlayerInput {
+                        thrust: (tick / 10 + id) % 2 == 1,
+                        target_angle: Some(((tick / 15 + id) % 8) as f32 * 0.785),
+                        fire: true,
+                        seq: tick as u32,
+                    };
+                    (id, input)
+                })
+                .collect();
+            world.step(&inputs, TICK_DT);
+            snapshots.push(world.snapshot());
+        }
+        snapshots
+    }
+
+    #[test]
+    fn applying_a_diff_rebuilds_the_newer_snapshot() {
+        let snapshots = recorded_snapshots(400);
+        for gap in [1, 5, 30] {
+            for pair in snapshots.windows(gap + 1) {
+                let (base, target) = (&pair[0], &pair[gap]);
+                let delta = base.diff(target);
+                assert_eq!(base.apply(&delta).as_ref(), Some(target));
+            }
+        }
+    }
+
+    #[test]
+    fn timers_and_health_do_not_resend_the_player() {
+        let snapshots = recorded_snapshots(1);
+        let base = &snapshots[1];
+        let mut target = base.clone();
+        target.tick += 1;
+        let player = &mut target.players[0];
+        player.effects.invincible_remaining = Some(1.0);
+        player.health -= 10.0;
+        player.last_input_seq += 1;
+
+        let delta = base.diff(&target);
+        assert!(delta.players.upserted.is_empty());
+        assert_eq!(delta.player_effects.len(), 1);
+        assert_eq!(delta.player_health.len(), 1);
+        assert_eq!(delta.players.moved.len(), 1);
+        assert_eq!(base.apply(&delta), Some(target));
+    }
+
+    #[test]
+    fn a_diff_only_applies_to_its_base() {
+        let snapshots = recorded_snapshots(2);
+        let delta = snapshots[1].diff(&snapshots[2]);
+        assert_eq!(snapshots[0].apply(&delta), None);
+    }
+}
@@ -0,0 +1,48 @@
+//! Datagrams for the optional UDP channel.
+//!
+//! Clients that negotiate [`Capabilities::UDP_TRANSPORT`](crate::Capabilities::UDP_TRANSPORT)
+//! get a token in `Welcome`. They then send [`ClientDatagram`]s carrying that
+//! token to the server's UDP socket (the same address as the TCP listener),
+//! which binds the datagram's source address to their session. From then on
+//! snapshots travel as [`ServerDatagram`]s and inputs and acks go the other
+//! way, so one lost packet no longer holds up everything behind it. Join,
+//! chat and system messages stay on TCP, as do snapshots too large for one
+//! datagram.
+//!
+//! Datagrams are always in the binary encoding, without a length prefix; see
+//! [`codec::encode_datagram`](crate::codec::encode_datagram).
+
+use serde::{Deserialize, Serialize};
+
+use crate::{PlayerInput, ServerMsg};
+
+/// Largest datagram either side sends. Bigger snapshots go over TCP instead
+/// so they never depend on IP fragmentation.
+pub const MAX_DATAGRAM_LEN: usize = 1200;
+
+/// How many of its most recent inputs a client repeats in every datagram, so
+/// a lost datagram costs nothing as long as one of the next few arrives.
+pub const INPUT_REDUNDANCY: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientDatagram {
+    /// Session token from `Welcome`.
+    pub token: u64,
+    /// Increases with every datagram; the server ignores older ones.
+    pub seq: u32,
+    /// Newest snapshot the client holds, as in `ClientMsg::Ack`.
+    pub ack_tick: Option<u64>,
+    /// Most recent sequenced inputs, oldest first. Inputs the server has
+    /// already seen are skipped by their `seq`.
+    pub inputs: Vec<PlayerInput>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerDatagram {
+    /// Increases with every datagram to this client; older ones are dropped.
+    pub seq: u32,
+    /// Room the message belongs to. Datagrams still in flight when the client
+    /// switches rooms are dropped by this.
+    pub room_id: u64,
+    pub msg: ServerMsg,
+}